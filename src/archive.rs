@@ -0,0 +1,306 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+
+use crate::builder::Options;
+use crate::checkpoint::CheckpointWriter;
+
+pub(crate) const MANIFEST_NAME: &str = "partial-tar-brotli-manifest.json";
+
+pub(crate) fn flush_and_get_position(
+    archive: &mut tar::Builder<CheckpointWriter<'_>>,
+) -> Result<u64> {
+    archive.get_mut().flush_and_get_position()
+}
+
+pub(crate) fn add_manifest<W: Write>(manifest: &str, archive: &mut tar::Builder<W>) -> Result<()> {
+    let manifest_data = manifest.as_bytes();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_data.len() as u64);
+    header.set_mode(0o644);
+
+    archive
+        .append_data(&mut header, MANIFEST_NAME, manifest_data)
+        .context("Could not add manifest to archive")?;
+
+    Ok(())
+}
+
+/// The manifest as a tar entry (without end of archive marker).
+pub(crate) fn manifest_entry(manifest: &str) -> Result<Vec<u8>> {
+    let mut archive = tar::Builder::new(Vec::new());
+    add_manifest(manifest, &mut archive)?;
+
+    Ok(std::mem::take(archive.get_mut()))
+}
+
+/// Size of a tar entry holding `len` bytes with a short name.
+pub(crate) fn tar_entry_size(len: u64) -> u64 {
+    512 + len.div_ceil(512) * 512
+}
+
+pub(crate) fn generate_archive_filename(orig: &Path) -> PathBuf {
+    let mut res = std::path::PathBuf::new();
+
+    for component in orig.components() {
+        match component {
+            std::path::Component::Normal(part) => {
+                if !part.is_empty() {
+                    res.push(part);
+                }
+            }
+            std::path::Component::CurDir => (),
+            std::path::Component::RootDir => {
+                res.clear();
+            }
+            std::path::Component::ParentDir => {
+                res.pop();
+            }
+            std::path::Component::Prefix(_) => todo!(),
+        }
+    }
+
+    res
+}
+
+/// Whether `file` is stored decompressed.
+pub(crate) fn decompresses(options: &Options, file: &Path) -> bool {
+    options.auto_decompress_gz && file.extension().unwrap_or_default() == "gz"
+}
+
+/// Decompressed size of the `.gz` `file` as recorded in its trailer,
+/// without decompressing it. That is modulo 4 GiB and of the last member
+/// only, so never taken below the size of the file itself.
+pub(crate) fn gz_size(file: &Path) -> Result<u64> {
+    let mut f = File::open(file).context("Could not open file")?;
+    let len = f.metadata().context("Could not get file metadata")?.len();
+    let mut recorded = [0; 4];
+    f.seek(std::io::SeekFrom::End(-4))
+        .and_then(|_| f.read_exact(&mut recorded))
+        .with_context(|| format!("Could not read gzip trailer of {}", file.display()))?;
+    Ok(std::cmp::max(u32::from_le_bytes(recorded) as u64, len))
+}
+
+/// What was learned about an input while adding it.
+pub(crate) struct Added {
+    pub(crate) decompressed_size: Option<u64>,
+    pub(crate) sha256: Option<String>,
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new() }
+    }
+
+    fn hex_digest(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Contents of `file` as they are stored, from `offset` on.
+pub(crate) fn open_contents(options: &Options, file: &Path, offset: u64) -> Result<Box<dyn Read>> {
+    let mut f = File::open(file).context("Could not open file")?;
+    if decompresses(options, file) {
+        let mut gz = GzDecoder::new(f);
+        std::io::copy(&mut (&mut gz).take(offset), &mut std::io::sink())
+            .context("Could not decompress file")?;
+        return Ok(Box::new(gz));
+    }
+
+    f.seek(std::io::SeekFrom::Start(offset)).context("Could not seek file")?;
+    Ok(Box::new(f))
+}
+
+/// Appends `file` from the filesystem as it is, stored as `name`. Files
+/// stored decompressed are decompressed with [`prepare_file`] first.
+pub(crate) fn add_file_to_archive<W: Write>(
+    archive: &mut tar::Builder<W>,
+    file: &Path,
+    name: &Path,
+) -> Result<Added> {
+    let f = File::open(file).context("Could not open file")?;
+    let metadata = f.metadata().context("Could not get file metadata")?;
+    if !metadata.is_file() {
+        archive.append_path_with_name(file, name).context("Could not add file to archive")?;
+        return Ok(Added { decompressed_size: None, sha256: None });
+    }
+
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tar::HeaderMode::Deterministic);
+
+    let mut reader = HashingReader::new(f);
+    archive.append_data(&mut header, name, &mut reader).context("Could not add file to archive")?;
+
+    Ok(Added { decompressed_size: None, sha256: Some(reader.hex_digest()) })
+}
+
+/// A file read ahead of adding it: decompressed to a temporary file
+/// when stored decompressed, hashed otherwise.
+pub(crate) struct Prepared {
+    /// The decompressed copy, deleted once done with it.
+    copy: Option<tempfile::TempPath>,
+    file: PathBuf,
+    metadata: Box<std::fs::Metadata>,
+    /// Length of the contents as stored.
+    pub(crate) size: u64,
+    sha256: String,
+    /// Estimated compressed size, see [`Options::prescreen`].
+    pub(crate) screened_size: Option<u64>,
+}
+
+impl Prepared {
+    /// Where the contents as stored are read from.
+    pub(crate) fn path(&self) -> &Path {
+        self.copy.as_deref().unwrap_or(&self.file)
+    }
+
+    pub(crate) fn decompressed_size(&self) -> Option<u64> {
+        self.copy.as_ref().map(|_| self.size)
+    }
+}
+
+/// Decompresses `file` to a temporary file if it is stored decompressed,
+/// otherwise hashes it.
+pub(crate) fn prepare_file(options: &Options, file: &Path) -> Result<Prepared> {
+    if !decompresses(options, file) {
+        let f = File::open(file).context("Could not open file")?;
+        let metadata = f.metadata().context("Could not get file metadata")?;
+        let mut reader = HashingReader::new(f);
+        let size = std::io::copy(&mut reader, &mut std::io::sink())
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        return Ok(Prepared {
+            copy: None,
+            file: file.to_path_buf(),
+            metadata: Box::new(metadata),
+            size,
+            sha256: reader.hex_digest(),
+            screened_size: None,
+        });
+    }
+
+    options.note(format_args!("Decompressing {} for better compression.", file.display()));
+
+    let mut uncompressed = tempfile::NamedTempFile::new()?;
+    let mut gz = HashingReader::new(GzDecoder::new(File::open(file)?));
+    let decompressed_size = std::io::copy(&mut gz, &mut uncompressed)?;
+    let metadata = uncompressed.as_file().metadata().context("Could not get file metadata")?;
+
+    Ok(Prepared {
+        copy: Some(uncompressed.into_temp_path()),
+        file: file.to_path_buf(),
+        metadata: Box::new(metadata),
+        size: decompressed_size,
+        sha256: gz.hex_digest(),
+        screened_size: None,
+    })
+}
+
+/// Appends the `prepared` file, stored as `name`. A file hashed ahead
+/// that has been modified since is hashed again while appending it.
+pub(crate) fn add_prepared_to_archive<W: Write>(
+    archive: &mut tar::Builder<W>,
+    prepared: &Prepared,
+    name: &Path,
+) -> Result<Added> {
+    if prepared.copy.is_none() {
+        let unchanged = std::fs::metadata(&prepared.file).is_ok_and(|m| {
+            m.len() == prepared.metadata.len()
+                && m.modified().ok() == prepared.metadata.modified().ok()
+        });
+        if !unchanged {
+            return add_file_to_archive(archive, &prepared.file, name);
+        }
+    }
+
+    let f = File::open(prepared.path()).context("Could not open file")?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&prepared.metadata, tar::HeaderMode::Deterministic);
+    archive.append_data(&mut header, name, f).context("Could not add file to archive")?;
+
+    Ok(Added {
+        decompressed_size: prepared.decompressed_size(),
+        sha256: Some(prepared.sha256.clone()),
+    })
+}
+
+/// Appends `size` bytes read from `data` as a regular file called `name`.
+pub(crate) fn add_data_to_archive<W: Write, R: Read>(
+    archive: &mut tar::Builder<W>,
+    name: &Path,
+    size: u64,
+    data: R,
+) -> Result<Added> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+
+    let mut reader = HashingReader::new(data);
+    archive.append_data(&mut header, name, &mut reader).context("Could not add data to archive")?;
+
+    Ok(Added { decompressed_size: None, sha256: Some(reader.hex_digest()) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    #[test]
+    fn test_generate_archive_filename() {
+        fn check(orig: &str, exp: &str) {
+            let p: PathBuf = orig.into();
+            let expected: OsString = exp.into();
+
+            let res = generate_archive_filename(&p);
+            assert_eq!(res.as_os_str(), expected);
+
+            // double normalization gives same
+            assert_eq!(generate_archive_filename(&res), expected);
+        }
+        fn check_unchanged(path: &str) {
+            check(path, path);
+        }
+
+        check_unchanged("test.txt");
+        check_unchanged("foo/test.txt");
+        check("foo//test.txt", "foo/test.txt");
+        check("foo/test.txt//", "foo/test.txt");
+        check("../some/file", "some/file");
+        check("some/file/buried/../deep/down", "some/file/deep/down");
+        check("/file/with/absolute/path", "file/with/absolute/path");
+        check("/file/with/absolute/../path", "file/with/path");
+        check("/../../crazy", "crazy");
+    }
+
+    #[test]
+    fn test_gz_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zeros.gz");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(&[0; 100000]).unwrap();
+        std::fs::write(&path, gz.finish().unwrap()).unwrap();
+        assert_eq!(gz_size(&path).unwrap(), 100000);
+
+        std::fs::write(&path, b"no").unwrap();
+        assert!(gz_size(&path).is_err());
+    }
+}
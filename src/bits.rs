@@ -0,0 +1,23 @@
+/// Bits are packed starting from the least significant bit of each
+/// byte, as both brotli [RFC7932 2] and deflate [RFC1951 3.1.1] do.
+pub(crate) struct BitWriter {
+    pub(crate) bytes: Vec<u8>,
+    bit_pos: usize,
+}
+
+impl BitWriter {
+    pub(crate) fn new() -> Self {
+        BitWriter { bytes: Vec::new(), bit_pos: 0 }
+    }
+
+    pub(crate) fn write(&mut self, nbits: usize, value: u32) {
+        for i in 0..nbits {
+            if self.bit_pos.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (self.bit_pos % 8);
+            self.bit_pos += 1;
+        }
+    }
+}
@@ -0,0 +1,2626 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use crate::archive::{
+    add_data_to_archive, add_file_to_archive, add_manifest, add_prepared_to_archive, decompresses,
+    flush_and_get_position, generate_archive_filename, gz_size, manifest_entry, open_contents,
+    prepare_file, tar_entry_size, Added,
+};
+use crate::checkpoint::{is_over_limit, CheckpointWriter};
+use crate::compressor::Compression;
+use crate::cut::{first_line_start, json_prefix, last_line_end};
+use crate::manifest::{index_json, Manifest, ManifestBound};
+use crate::prepare::{prepare_ahead, Pending};
+use crate::select::{compare, knapsack, round_robin, walk, Filter};
+use crate::sink::{FileSink, LazyFileSink, NullSink, Sink, StreamSink, TeeSink};
+use crate::tune::{choose_quality, quick_ratio};
+
+/// Compression format of the archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// `.tar.br`
+    #[default]
+    Brotli,
+    /// `.tar.zst`
+    Zstd,
+    /// `.tar.gz`
+    Gzip,
+    /// `.tar`
+    Uncompressed,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "brotli" => Ok(Format::Brotli),
+            "zstd" => Ok(Format::Zstd),
+            "gzip" => Ok(Format::Gzip),
+            "none" => Ok(Format::Uncompressed),
+            _ => bail!("Unknown format {:?}, expected brotli, zstd, gzip or none", s),
+        }
+    }
+}
+
+/// Order the inputs are tried in, and so which of them win the budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Order {
+    /// As they were added.
+    #[default]
+    Given,
+    /// Most recently modified first.
+    MtimeNewest,
+    /// Least recently modified first.
+    MtimeOldest,
+    /// Smallest first, which fits the most inputs.
+    SizeAscending,
+    /// By name, with numbers in it compared by value (`dump-9` before
+    /// `dump-10`).
+    Name,
+    /// By the Unix timestamp in the file name, like in
+    /// `slowness-1724087161.json`, newest first.
+    TimestampNewest,
+    /// Like [`TimestampNewest`](Self::TimestampNewest), oldest first.
+    TimestampOldest,
+}
+
+impl std::str::FromStr for Order {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "given" => Ok(Order::Given),
+            "mtime-newest" => Ok(Order::MtimeNewest),
+            "mtime-oldest" => Ok(Order::MtimeOldest),
+            "size-ascending" => Ok(Order::SizeAscending),
+            "name" => Ok(Order::Name),
+            "timestamp-newest" => Ok(Order::TimestampNewest),
+            "timestamp-oldest" => Ok(Order::TimestampOldest),
+            _ => bail!(
+                "Unknown order {:?}, expected given, mtime-newest, mtime-oldest, \
+                 size-ascending, name, timestamp-newest or timestamp-oldest",
+                s
+            ),
+        }
+    }
+}
+
+/// What [`Options::optimize`] maximizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    /// Number of inputs.
+    Count,
+    /// Total size of the inputs (decompressed, for `.gz` files stored
+    /// decompressed).
+    Bytes,
+    /// Total weight of the inputs, where an input weighs its
+    /// [`Priority`] above the lowest one plus one. Between equal totals
+    /// the inputs earlier in [`Options::order`] win.
+    Priority,
+}
+
+impl std::str::FromStr for Objective {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "count" => Ok(Objective::Count),
+            "bytes" => Ok(Objective::Bytes),
+            "priority" => Ok(Objective::Priority),
+            _ => bail!("Unknown objective {:?}, expected count, bytes or priority", s),
+        }
+    }
+}
+
+/// How much an input is wanted, from [`Options::priorities`]. Inputs
+/// with a higher priority are tried first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Inputs without a rule have priority 0.
+    Weight(i64),
+    /// Must be included, packing fails otherwise.
+    Must,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Weight(0)
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "must" => Ok(Priority::Must),
+            _ => Ok(Priority::Weight(s.parse().with_context(|| {
+                format!("Invalid priority {:?}, expected a number or must", s)
+            })?)),
+        }
+    }
+}
+
+/// Part of the budget for a [`Group`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Share {
+    /// A fixed number of compressed bytes.
+    Bytes(u64),
+    /// Percent of what the budget leaves for the inputs.
+    Percent(u64),
+}
+
+impl Share {
+    /// The quota in bytes, out of `capacity` bytes for all inputs.
+    pub(crate) fn of(self, capacity: u64) -> u64 {
+        match self {
+            Share::Bytes(bytes) => bytes,
+            Share::Percent(percent) => (capacity as u128 * percent as u128 / 100) as u64,
+        }
+    }
+}
+
+impl std::str::FromStr for Share {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format!("Invalid share {:?}, expected bytes or a percentage", s);
+        match s.strip_suffix('%') {
+            Some(percent) => Ok(Share::Percent(percent.parse().with_context(invalid)?)),
+            None => Ok(Share::Bytes(s.parse().with_context(invalid)?)),
+        }
+    }
+}
+
+/// How an input that does not fit whole is cut, see [`Options::partial`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cut {
+    /// Half from the beginning and half from the end, cut anywhere.
+    Bytes,
+    /// Like [`Bytes`](Self::Bytes), but only whole lines.
+    Lines,
+    /// JSON inputs (named `*.json`) keep their beginning, without the
+    /// array elements and object members cut off and with the arrays
+    /// and objects open there closed, so they remain valid JSON. Other
+    /// inputs like [`Lines`](Self::Lines).
+    Json,
+}
+
+impl std::str::FromStr for Cut {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bytes" => Ok(Cut::Bytes),
+            "lines" => Ok(Cut::Lines),
+            "json" => Ok(Cut::Json),
+            _ => bail!("Unknown cut {:?}, expected bytes, lines or json", s),
+        }
+    }
+}
+
+/// What was kept of an input stored partially.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partial {
+    /// Byte ranges of the contents stored, one after the other.
+    pub kept: Vec<Range<u64>>,
+    /// How it was actually cut, [`Cut::Lines`] for JSON inputs that
+    /// could not be cut as JSON.
+    pub cut: Cut,
+    /// Appended to the kept contents, closing the JSON arrays and
+    /// objects open there.
+    pub(crate) closing: Vec<u8>,
+}
+
+impl Partial {
+    /// The beginning up to `head_end` and the end from `tail_start`.
+    fn new(head_end: u64, tail_start: u64, size: u64, cut: Cut) -> Self {
+        let kept = [0..head_end, tail_start..size].into_iter().filter(|r| !r.is_empty());
+        Partial { kept: kept.collect(), cut, closing: Vec::new() }
+    }
+
+    /// Number of bytes stored.
+    fn len(&self) -> u64 {
+        self.kept.iter().map(|r| r.end - r.start).sum::<u64>() + self.closing.len() as u64
+    }
+}
+
+/// Settings of the brotli encoder, see [`Options::brotli`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrotliParams {
+    /// From 0 (fastest) to 11 (smallest).
+    pub quality: u32,
+    /// Base 2 logarithm of the window size, from 10 to 24, or up to 30
+    /// with [`large_window`](Self::large_window).
+    pub lgwin: u32,
+    /// Brotli's large window extension, which decoders must be told
+    /// about, like `brotli --large_window=30 -d`.
+    pub large_window: bool,
+    /// Size in bytes of the encoder's buffer.
+    pub buffer_size: usize,
+}
+
+impl BrotliParams {
+    /// Fails unless the settings are within brotli's limits.
+    pub fn check(&self) -> Result<()> {
+        if self.quality > 11 {
+            bail!("Unknown brotli quality {}, expected 0 to 11", self.quality);
+        }
+        let max_lgwin = if self.large_window { 30 } else { 24 };
+        if !(10..=max_lgwin).contains(&self.lgwin) {
+            bail!("Unknown brotli window {}, expected 10 to {}", self.lgwin, max_lgwin);
+        }
+        if self.buffer_size == 0 {
+            bail!("Brotli buffer size must not be 0");
+        }
+        Ok(())
+    }
+}
+
+impl Default for BrotliParams {
+    fn default() -> Self {
+        BrotliParams { quality: 11, lgwin: 22, large_window: false, buffer_size: 4096 }
+    }
+}
+
+/// Inputs that share a quota, see [`Options::groups`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub share: Share,
+    /// Glob patterns matched against the names in the archive.
+    pub patterns: Vec<String>,
+    /// Files read from, and directories for the files below them.
+    pub paths: Vec<PathBuf>,
+}
+
+/// Receives progress information, see [`Options::progress`].
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl Progress {
+    pub fn new(progress: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Progress(Arc::new(progress))
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Settings that affect how inputs are added to the archive.
+///
+/// More settings may come, so start from [`Options::default`] and set
+/// the fields that matter.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Options {
+    /// Called with a line of progress information at a time, e.g. to
+    /// print it to stderr. Also called from the threads of
+    /// [`jobs`](Self::jobs).
+    pub progress: Option<Progress>,
+
+    /// Store decompressed contents of `.gz` files (without the
+    /// extension) as brotli compresses them a lot better.
+    pub auto_decompress_gz: bool,
+
+    /// Keep trying the remaining inputs after one does not fit, instead
+    /// of stopping there. Costs recompressing what has been added so
+    /// far each time an input is skipped, and a temporary file as large
+    /// as the uncompressed archive to recompress it from. The same goes
+    /// for [`groups`](Self::groups) and [`partial`](Self::partial).
+    pub skip_oversized: bool,
+
+    /// Before compressing an input that is bigger than the room left,
+    /// estimate its compressed size from a few samples of it compressed
+    /// at brotli quality 1, and pass over it without compressing it
+    /// when even half the estimate does not fit. Readers are not
+    /// screened, nor inputs with [`Priority::Must`].
+    pub prescreen: bool,
+
+    /// Compression format of the archive.
+    pub format: Format,
+
+    /// Settings for [`Format::Brotli`], see [`BrotliParams::check`].
+    pub brotli: BrotliParams,
+
+    /// Lower the brotli quality until compressing the inputs once is
+    /// projected to take at most this long, timed on a sample of them.
+    /// Taking the sample, recompressing and extra passes are not
+    /// covered. The quality is fixed before writing, as brotli can't
+    /// change it mid-stream, and is not lowered should compressing take
+    /// longer than projected. Readers are read into memory.
+    pub max_time: Option<Duration>,
+
+    /// Choose the brotli quality that is projected to fit the most
+    /// inputs in the budget, the fastest one on a tie, from how a sample
+    /// of them compresses. Readers are read into memory.
+    pub auto_quality: bool,
+
+    /// Only add inputs whose name in the archive matches one of these
+    /// glob patterns, or all of them when empty.
+    pub include: Vec<String>,
+
+    /// Leave out inputs whose name in the archive matches one of these
+    /// glob patterns.
+    pub exclude: Vec<String>,
+
+    /// Leave out what `.gitignore` and `.ignore` files exclude when
+    /// walking directories.
+    pub respect_ignore_files: bool,
+
+    /// Order the inputs are tried in. Inputs lacking what they are
+    /// ordered by (like the modification time of in-memory data) come
+    /// last, in the order they were added.
+    pub order: Order,
+
+    /// Write the inputs that fit in this order instead. What fits is
+    /// still decided in [`order`](Self::order), e.g. the newest inputs
+    /// win the budget but are stored oldest first. Costs compressing
+    /// everything at least twice more, readers are read into memory.
+    pub write_order: Option<Order>,
+
+    /// Choose the inputs to try up front instead of adding them until
+    /// one does not fit. Each input is compressed alone first to
+    /// estimate what it adds to the archive, then the set of inputs
+    /// whose estimates fit that is best by this objective is picked.
+    /// Costs compressing everything twice, readers are read into
+    /// memory.
+    pub optimize: Option<Objective>,
+
+    /// Glob patterns matched against the names in the archive, with the
+    /// priority of the inputs they match. The first matching pattern
+    /// counts. Inputs are tried by priority first, and in
+    /// [`order`](Self::order) within the same priority.
+    pub priorities: Vec<(String, Priority)>,
+
+    /// Inputs are first added only as long as their group stays within
+    /// its quota. Then what some groups left unused is given to the
+    /// inputs of the others that did not fit their quota, in the order
+    /// they were tried. Inputs belong to the first group that they
+    /// match, those in no group have no quota.
+    pub groups: Vec<Group>,
+
+    /// Take turns between the [`groups`](Self::groups) instead, trying
+    /// the first input of each group, then the second of each group
+    /// and so on. Inputs in no group come last.
+    pub round_robin: bool,
+
+    /// Store about as much of an input that does not fit as does, cut
+    /// like this, named like the input with `.partial` appended. Two
+    /// lengths are tried by how the input compresses, then halves of the
+    /// last until one fits, each costing recompressing what has been
+    /// added so far when it does not fit. Readers are read into memory.
+    pub partial: Option<Cut>,
+
+    /// Read files in this many worker threads, ahead of compressing
+    /// them: decompress the `.gz` files stored decompressed (see
+    /// [`auto_decompress_gz`](Self::auto_decompress_gz)), hash the others
+    /// and, with [`prescreen`](Self::prescreen), estimate them all. Each
+    /// decompressed copy is deleted once its file is settled or passed
+    /// over, and at most twice as many files as threads are held while
+    /// the workers run ahead. Files that are read again, with
+    /// [`optimize`](Self::optimize), [`write_order`](Self::write_order)
+    /// or volumes, are held until done. With 0 each file is read as it
+    /// is added.
+    pub jobs: usize,
+}
+
+impl Options {
+    /// Passes `message` on to [`progress`](Self::progress), if any.
+    pub(crate) fn note(&self, message: std::fmt::Arguments) {
+        if let Some(Progress(progress)) = &self.progress {
+            progress(&message.to_string());
+        }
+    }
+}
+
+enum Source<'a> {
+    Path(PathBuf),
+    Reader(u64, Box<dyn Read + 'a>),
+    Data(Vec<u8>),
+    /// A file read ahead of adding it, in the background with
+    /// [`Options::jobs`].
+    Pending(Pending),
+}
+
+struct Input<'a> {
+    name: Option<PathBuf>,
+    source: Source<'a>,
+}
+
+impl<'a> Input<'a> {
+    /// Directories are replaced by the files below them, stored under
+    /// the directory's name.
+    fn expand(self, options: &Options) -> Result<Vec<Input<'a>>> {
+        let Source::Path(dir) = &self.source else {
+            return Ok(vec![self]);
+        };
+        if !dir.is_dir() {
+            return Ok(vec![self]);
+        }
+
+        let files = walk(dir, options.respect_ignore_files)?;
+        Ok(files
+            .into_iter()
+            .map(|path| {
+                let name = self.name.as_ref().map(|name| {
+                    let name = name.join(path.strip_prefix(dir).expect("walked below dir"));
+                    if decompresses(options, &path) {
+                        name.with_extension("")
+                    } else {
+                        name
+                    }
+                });
+                Input { name, source: Source::Path(path) }
+            })
+            .collect())
+    }
+
+    /// Reads a reader into memory, so that the input can be added more
+    /// than once.
+    fn buffer(&mut self) -> Result<()> {
+        if let Source::Reader(size, reader) = &mut self.source {
+            let mut data = vec![0; *size as usize];
+            reader.read_exact(&mut data).context("Could not read input")?;
+            self.source = Source::Data(data);
+        }
+        Ok(())
+    }
+
+    /// Appends the input to `archive`, recording what was learned while
+    /// reading it in `entry`.
+    fn add_to<W: Write>(
+        &mut self,
+        options: &Options,
+        archive: &mut tar::Builder<W>,
+        entry: &mut EntryReport,
+    ) -> Result<()> {
+        /* The decompressed size is recorded before appending, as that is
+         * abandoned when the archive goes over its limit */
+        let added = match &mut self.source {
+            Source::Path(path) if decompresses(options, path) => {
+                let prepared = prepare_file(options, path)?;
+                entry.decompressed_size = prepared.decompressed_size();
+                add_prepared_to_archive(archive, &prepared, &entry.name)?
+            }
+            Source::Path(path) => add_file_to_archive(archive, path, &entry.name)?,
+            Source::Pending(pending) if decompresses(options, pending.path()) => {
+                let prepared = pending.get()?;
+                entry.decompressed_size = prepared.decompressed_size();
+                add_prepared_to_archive(archive, prepared, &entry.name)?
+            }
+            /* Hashed while adding it unless a worker did */
+            Source::Pending(pending) => match pending.ready()? {
+                Some(prepared) => add_prepared_to_archive(archive, prepared, &entry.name)?,
+                None => add_file_to_archive(archive, pending.path(), &entry.name)?,
+            },
+            Source::Reader(size, reader) => {
+                add_data_to_archive(archive, &entry.name, *size, reader)?
+            }
+            Source::Data(data) => {
+                add_data_to_archive(archive, &entry.name, data.len() as u64, &data[..])?
+            }
+        };
+        entry.decompressed_size = added.decompressed_size;
+        entry.sha256 = added.sha256;
+        Ok(())
+    }
+
+    /// Has a file stored decompressed decompressed only once, when it is
+    /// first read, and then read from the decompressed copy until it is
+    /// released.
+    fn decompress_once(&mut self, options: &Options) {
+        match &self.source {
+            Source::Path(path) if decompresses(options, path) => {
+                /* Without workers (they prepared all of them already) */
+                let mut pending = prepare_ahead(options, vec![path.clone()]);
+                self.source = Source::Pending(pending.remove(0));
+            }
+            _ => (),
+        }
+    }
+
+    /// Deletes what was decompressed ahead for the input, see
+    /// [`Options::jobs`]. Should it be read again, it is decompressed
+    /// again.
+    fn release(&mut self) {
+        if let Source::Pending(pending) = &mut self.source {
+            pending.release();
+        }
+    }
+
+    /// Length of the input's contents as stored. Decompresses `.gz` files
+    /// to count it, unless it is known already.
+    fn stored_len(&self, options: &Options, entry: &mut EntryReport) -> Result<u64> {
+        if let Some(size) = entry.decompressed_size {
+            return Ok(size);
+        }
+        let size = match &self.source {
+            Source::Path(path) if decompresses(options, path) => {
+                std::io::copy(&mut open_contents(options, path, 0)?, &mut std::io::sink())
+                    .context("Could not decompress file")?
+            }
+            Source::Pending(pending) if decompresses(options, pending.path()) => {
+                pending.get()?.size
+            }
+            _ => return Ok(entry.size),
+        };
+        entry.decompressed_size = Some(size);
+        Ok(size)
+    }
+
+    /// Length of the input's contents as stored, to project how long
+    /// compressing takes. `.gz` files are not decompressed to count it,
+    /// the length their trailer records is taken instead.
+    fn projected_len(&self, options: &Options, entry: &EntryReport) -> Result<u64> {
+        if let Some(size) = entry.decompressed_size {
+            return Ok(size);
+        }
+        match &self.source {
+            Source::Path(path) if decompresses(options, path) => gz_size(path),
+            Source::Pending(pending) if decompresses(options, pending.path()) => {
+                gz_size(pending.path())
+            }
+            _ => Ok(entry.size),
+        }
+    }
+
+    /// The input's contents as stored, from `offset` on.
+    fn open_at(&self, options: &Options, offset: u64) -> Result<Box<dyn Read + '_>> {
+        match &self.source {
+            Source::Path(path) => open_contents(options, path, offset),
+            Source::Pending(pending) if decompresses(options, pending.path()) => {
+                open_contents(options, pending.get()?.path(), offset)
+            }
+            Source::Pending(pending) => open_contents(options, pending.path(), offset),
+            Source::Reader(..) => unreachable!("readers are buffered or not opened"),
+            Source::Data(data) => Ok(Box::new(&data[offset as usize..])),
+        }
+    }
+
+    /// What to keep of the `size` bytes of contents of the input stored
+    /// as `name`, in at most `len` bytes.
+    fn part(
+        &self,
+        options: &Options,
+        name: &Path,
+        size: u64,
+        len: u64,
+        cut: Cut,
+    ) -> Result<Partial> {
+        let (head, tail) = (len.div_ceil(2), len / 2);
+        match cut {
+            Cut::Bytes => Ok(Partial::new(head, size - tail, size, Cut::Bytes)),
+            Cut::Json if name.extension().is_some_and(|e| e.eq_ignore_ascii_case("json")) => {
+                let Some((end, closing)) =
+                    json_prefix(self.open_at(options, 0)?, len).context("Could not read input")?
+                else {
+                    return self.part(options, name, size, len, Cut::Lines);
+                };
+                Ok(Partial { closing, ..Partial::new(end, size, size, Cut::Json) })
+            }
+            Cut::Lines | Cut::Json => {
+                let head_end = last_line_end(self.open_at(options, 0)?, head)
+                    .context("Could not read input")?;
+                /* From the line starting at or after size - tail */
+                let tail_start = match size - tail {
+                    0 => 0,
+                    start => first_line_start(self.open_at(options, start - 1)?)
+                        .context("Could not read input")?
+                        .map_or(size, |n| start - 1 + n),
+                };
+                Ok(Partial::new(head_end, tail_start, size, Cut::Lines))
+            }
+        }
+    }
+
+    /// Appends `part` of the input as `name`.
+    fn add_part<W: Write>(
+        &self,
+        options: &Options,
+        archive: &mut tar::Builder<W>,
+        name: &Path,
+        part: &Partial,
+    ) -> Result<Added> {
+        let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+        for range in &part.kept {
+            let kept = self.open_at(options, range.start)?.take(range.end - range.start);
+            reader = Box::new(reader.chain(kept));
+        }
+        add_data_to_archive(archive, name, part.len(), reader.chain(&part.closing[..]))
+    }
+
+    /// Compressed size of the `size` bytes of the input as stored (see
+    /// [`stored_len`](Self::stored_len)), estimated from compressing
+    /// samples spread over it quickly, `None` for readers.
+    fn screen(&self, options: &Options, size: u64) -> Result<Option<u64>> {
+        match &self.source {
+            Source::Reader(..) => return Ok(None),
+            Source::Pending(pending) => {
+                let ready = if decompresses(options, pending.path()) {
+                    Some(pending.get()?)
+                } else {
+                    pending.ready()?
+                };
+                if let Some(screened_size) = ready.and_then(|prepared| prepared.screened_size) {
+                    return Ok(Some(screened_size));
+                }
+            }
+            _ => (),
+        }
+        let open_at = |offset| self.open_at(options, offset);
+        Ok(Some(screen_contents(options, size, open_at)?))
+    }
+
+    /// Compressed size of the input in an archive of its own.
+    fn estimate(&mut self, options: &Options, entry: &mut EntryReport) -> Result<u64> {
+        let output = Box::new(NullSink::default());
+        let mut archive =
+            tar::Builder::new(CheckpointWriter::new(output, Compression::of(options), false)?);
+        archive.mode(tar::HeaderMode::Deterministic);
+
+        let start_pos = archive.get_mut().checkpoint()?;
+        self.add_to(options, &mut archive, entry)?;
+        Ok(flush_and_get_position(&mut archive)? - start_pos)
+    }
+
+    /// Report for the input before anything has been done with it.
+    fn report(&self, options: &Options) -> Result<EntryReport> {
+        let (source, size, mtime) = match &self.source {
+            Source::Path(path) => {
+                let metadata = std::fs::metadata(path)
+                    .with_context(|| format!("Could not get metadata of {}", path.display()))?;
+                let mtime = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs());
+                (Some(path.clone()), metadata.len(), mtime)
+            }
+            Source::Reader(size, _) => (None, *size, None),
+            Source::Data(data) => (None, data.len() as u64, None),
+            Source::Pending(_) => unreachable!("inputs are prepared once reported on"),
+        };
+
+        let name = match (&self.name, &source) {
+            (Some(name), _) => generate_archive_filename(name),
+            (None, Some(path)) if decompresses(options, path) => {
+                generate_archive_filename(&path.with_extension(""))
+            }
+            (None, Some(path)) => generate_archive_filename(path),
+            (None, None) => unreachable!("non-path inputs always have a name"),
+        };
+
+        Ok(EntryReport { mtime, ..EntryReport::new(source, name, size) })
+    }
+}
+
+/// Why an input did not end up in the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The input was added but made the archive exceed the budget.
+    DoesNotFit,
+    /// Packing stopped before the input was tried.
+    NotAttempted,
+    /// [`Options::optimize`] left the input out.
+    NotSelected,
+    /// [`Options::prescreen`] estimated that the input could not fit,
+    /// so it was not compressed.
+    ScreenedOut,
+}
+
+/// What happened to an input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryStatus {
+    /// Stored in the archive, using `compressed_size` bytes of the budget.
+    Included {
+        compressed_size: u64,
+    },
+    Skipped(SkipReason),
+}
+
+/// Outcome for one input, in the order the inputs were tried.
+#[derive(Debug, Clone)]
+pub struct EntryReport {
+    /// Path the input was read from, `None` for readers and in-memory data.
+    pub source: Option<PathBuf>,
+    /// Name in the archive (or that it would have had).
+    pub name: PathBuf,
+    /// Size of the input, for decompressed `.gz` files that of the
+    /// compressed file.
+    pub size: u64,
+    /// Size after decompression, for inputs stored decompressed.
+    pub decompressed_size: Option<u64>,
+    /// Size estimated for choosing the inputs, with [`Options::optimize`].
+    pub estimated_size: Option<u64>,
+    /// Size estimated for screening the input, with [`Options::prescreen`].
+    pub screened_size: Option<u64>,
+    /// Modification time in seconds since the Unix epoch, for files.
+    pub mtime: Option<u64>,
+    /// SHA-256 of the contents as stored, for inputs that were read.
+    pub sha256: Option<String>,
+    pub priority: Priority,
+    /// Name of the [`Group`] the input belongs to.
+    pub group: Option<String>,
+    /// What was stored of inputs that did not fit whole, with
+    /// [`Options::partial`].
+    pub partial: Option<Partial>,
+    /// Number of the volume the input was stored in, starting at 1, for
+    /// [`PartialArchiveBuilder::new_volumes`].
+    pub volume: Option<usize>,
+    pub status: EntryStatus,
+}
+
+impl EntryReport {
+    /// Report for an input not tried yet, with nothing else known.
+    pub(crate) fn new(source: Option<PathBuf>, name: PathBuf, size: u64) -> Self {
+        EntryReport {
+            source,
+            name,
+            size,
+            decompressed_size: None,
+            estimated_size: None,
+            screened_size: None,
+            mtime: None,
+            sha256: None,
+            priority: Priority::default(),
+            group: None,
+            partial: None,
+            volume: None,
+            status: EntryStatus::Skipped(SkipReason::NotAttempted),
+        }
+    }
+
+    /// How the input is referred to in messages.
+    pub(crate) fn label(&self) -> &Path {
+        self.source.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// How much of its quota a [`Group`] used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupReport {
+    pub name: String,
+    pub quota: u64,
+    /// Compressed bytes of its inputs in the archive, which can be more
+    /// than the quota when other groups left room.
+    pub used: u64,
+    pub included: usize,
+    pub skipped: usize,
+}
+
+impl GroupReport {
+    pub(crate) fn new(name: &str, quota: u64, entries: &[EntryReport]) -> Self {
+        let mut report = GroupReport { name: name.into(), quota, used: 0, included: 0, skipped: 0 };
+        for entry in entries.iter().filter(|e| e.group.as_deref() == Some(name)) {
+            match entry.status {
+                EntryStatus::Included { compressed_size } => {
+                    report.used += compressed_size;
+                    report.included += 1;
+                }
+                EntryStatus::Skipped(_) => report.skipped += 1,
+            }
+        }
+        report
+    }
+}
+
+/// Result of [`PartialArchiveBuilder::finish`].
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub entries: Vec<EntryReport>,
+    /// Size in bytes of the finished archive, of all volumes together.
+    pub size: u64,
+    /// Usage of each of [`Options::groups`].
+    pub groups: Vec<GroupReport>,
+    /// Size in bytes of each volume written, for
+    /// [`PartialArchiveBuilder::new_volumes`].
+    pub volumes: Vec<u64>,
+    /// Reports of the archives added by
+    /// [`PartialArchiveBuilder::add_tier`], in the order they were added.
+    pub tiers: Vec<Report>,
+}
+
+impl Report {
+    pub fn included(&self) -> impl Iterator<Item = &EntryReport> {
+        self.entries.iter().filter(|e| matches!(e.status, EntryStatus::Included { .. }))
+    }
+
+    pub fn skipped(&self) -> impl Iterator<Item = &EntryReport> {
+        self.entries.iter().filter(|e| matches!(e.status, EntryStatus::Skipped(_)))
+    }
+
+    /// JSON index of which volume each input went in, for
+    /// [`PartialArchiveBuilder::new_volumes`].
+    pub fn index(&self) -> String {
+        index_json(self)
+    }
+}
+
+/// Where the archive goes.
+enum Output<'a> {
+    Single(Box<dyn Sink + 'a>),
+    /// Up to this many volumes, their files created by number as needed.
+    Volumes(usize, Box<dyn FnMut(usize) -> Result<File> + 'a>),
+}
+
+/// Builds a compressed tar archive that is never larger than a given
+/// budget.
+///
+/// Inputs are queued with the `add_*` methods and written by
+/// [`finish`](Self::finish), in the order they were added unless
+/// [`Options::order`] says otherwise. Once an input does not fit the archive is
+/// cut back to the end of the previous input and the remaining inputs
+/// are skipped, unless [`Options::skip_oversized`] is set.
+pub struct PartialArchiveBuilder<'a> {
+    output: Output<'a>,
+    max_size: u64,
+    options: Options,
+    inputs: Vec<Input<'a>>,
+    /// Further outputs with their budgets.
+    tiers: Vec<(Box<dyn Sink + 'a>, u64)>,
+}
+
+impl<'a> PartialArchiveBuilder<'a> {
+    /// `output` must be a new, empty file. Data that ends up not fitting
+    /// is removed again by truncating it.
+    pub fn new(output: File, max_size: u64, options: Options) -> Self {
+        Self::with_sink(Box::new(FileSink::new(output)), max_size, options)
+    }
+
+    /// Writes to `output` that can't be seeked or truncated, like a pipe
+    /// or socket. Data that might still have to be dropped is held back
+    /// in memory until it is known to fit, at most `max_size` bytes.
+    pub fn new_streaming<W: Write + 'a>(output: W, max_size: u64, options: Options) -> Self {
+        Self::with_sink(Box::new(StreamSink::new(output, max_size)), max_size, options)
+    }
+
+    /// Writes up to `volumes` archives, each within `max_size` and with
+    /// its own manifest. Inputs that do not fit in one volume go in the
+    /// next, until all inputs are stored or the volumes are used up.
+    /// Inputs that do not fit even in an empty volume are left out.
+    /// `create` is called with the number of each volume, starting at 1,
+    /// once something is stored in it, and must return a new, empty file.
+    /// Until then the volume is held in memory, at most `max_size` bytes.
+    pub fn new_volumes<F: FnMut(usize) -> Result<File> + 'a>(
+        create: F,
+        volumes: usize,
+        max_size: u64,
+        options: Options,
+    ) -> Self {
+        let output = Output::Volumes(volumes, Box::new(create));
+        PartialArchiveBuilder { output, max_size, options, inputs: Vec::new(), tiers: Vec::new() }
+    }
+
+    fn with_sink(output: Box<dyn Sink + 'a>, max_size: u64, options: Options) -> Self {
+        let output = Output::Single(output);
+        PartialArchiveBuilder { output, max_size, options, inputs: Vec::new(), tiers: Vec::new() }
+    }
+
+    /// Also writes the archive to `output`, a new, empty file, within
+    /// `max_size` instead. The inputs are compressed once for all
+    /// outputs, each output ends at the first input that does not fit
+    /// its budget.
+    ///
+    /// Only for packing that stops at the first input that does not fit,
+    /// which is the same for every budget: [`finish`](Self::finish)
+    /// fails when combined with [`Options::skip_oversized`],
+    /// [`Options::groups`], [`Options::partial`], [`Options::optimize`],
+    /// [`Options::write_order`] or volumes.
+    pub fn add_tier(&mut self, output: File, max_size: u64) -> &mut Self {
+        self.tiers.push((Box::new(FileSink::new(output)), max_size));
+        self
+    }
+
+    /// Like [`add_tier`](Self::add_tier), for `output` like in
+    /// [`new_streaming`](Self::new_streaming).
+    pub fn add_streaming_tier<W: Write + 'a>(&mut self, output: W, max_size: u64) -> &mut Self {
+        self.tiers.push((Box::new(StreamSink::new(output, max_size)), max_size));
+        self
+    }
+
+    /// Queues a file from the filesystem, stored under its (normalized)
+    /// path. A directory queues all files below it.
+    pub fn add_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.inputs.push(Input { name: None, source: Source::Path(path.into()) });
+        self
+    }
+
+    /// Queues a file from the filesystem, stored as `name`. A directory
+    /// queues all files below it, stored below `name`.
+    pub fn add_path_with_name<P: Into<PathBuf>, N: Into<PathBuf>>(
+        &mut self,
+        path: P,
+        name: N,
+    ) -> &mut Self {
+        self.inputs.push(Input { name: Some(name.into()), source: Source::Path(path.into()) });
+        self
+    }
+
+    /// Queues `size` bytes from `reader`, stored as `name`.
+    pub fn add_reader<N: Into<PathBuf>, R: Read + 'a>(
+        &mut self,
+        name: N,
+        size: u64,
+        reader: R,
+    ) -> &mut Self {
+        self.inputs.push(Input {
+            name: Some(name.into()),
+            source: Source::Reader(size, Box::new(reader)),
+        });
+        self
+    }
+
+    /// Queues in-memory `data`, stored as `name`.
+    pub fn add_data<N: Into<PathBuf>, D: Into<Vec<u8>>>(&mut self, name: N, data: D) -> &mut Self {
+        self.inputs.push(Input { name: Some(name.into()), source: Source::Data(data.into()) });
+        self
+    }
+
+    /// Writes the archive and reports what went into it.
+    ///
+    /// Fails if an input with [`Priority::Must`] did not fit, after the
+    /// archive has been written without it.
+    pub fn finish(self) -> Result<Report> {
+        let (max_size, options) = (self.max_size, self.options.clone());
+        let mut report = self.write()?;
+
+        let (capacity, _) = capacity(max_size, &options, &report.entries)?;
+        report.groups = options
+            .groups
+            .iter()
+            .map(|g| GroupReport::new(&g.name, g.share.of(capacity), &report.entries))
+            .collect();
+
+        let missing: Vec<_> = report
+            .skipped()
+            .filter(|e| e.priority == Priority::Must)
+            .map(|e| e.label().display().to_string())
+            .collect();
+        if !missing.is_empty() {
+            bail!("Must include {}, but it does not fit in the budget", missing.join(", "));
+        }
+
+        Ok(report)
+    }
+
+    fn write(self) -> Result<Report> {
+        let PartialArchiveBuilder { output, max_size, options, inputs, tiers } = self;
+
+        let filter = Filter::new(&options)?;
+        let mut selected = Vec::new();
+        for input in inputs {
+            for input in input.expand(&options)? {
+                let mut entry = input.report(&options)?;
+                entry.priority = filter.priority(&entry.name);
+                entry.group = filter.group(&entry);
+                if filter.matches(&entry.name) {
+                    selected.push((input, entry));
+                } else {
+                    options.note(format_args!("{} excluded.", entry.label().display()));
+                }
+            }
+        }
+        selected.sort_by(|(_, a), (_, b)| {
+            b.priority.cmp(&a.priority).then_with(|| compare(options.order, a, b))
+        });
+        if options.round_robin {
+            selected = round_robin(selected, |(_, entry)| {
+                options.groups.iter().position(|g| entry.group.as_ref() == Some(&g.name))
+            });
+        }
+        let (mut selected, mut entries): (Vec<_>, Vec<_>) = selected.into_iter().unzip();
+        if options.jobs > 0 {
+            prepare(&options, &mut selected);
+        }
+
+        /* Every pass reads the inputs again, and inputs that don't fit
+         * are read again to store part of them, and so are inputs that
+         * are tried in another volume or sampled for the quality */
+        let passes = options.optimize.is_some() || options.write_order.is_some();
+        let tuning = options.max_time.is_some() || options.auto_quality;
+        if passes || options.partial.is_some() || tuning || matches!(output, Output::Volumes(..)) {
+            for input in &mut selected {
+                input.buffer()?;
+            }
+        }
+        let options = if tuning { tuned(max_size, options, &selected, &entries)? } else { options };
+
+        let (volumes, mut create) = match output {
+            Output::Single(output) if !tiers.is_empty() => {
+                if passes
+                    || options.skip_oversized
+                    || !options.groups.is_empty()
+                    || options.partial.is_some()
+                {
+                    bail!("Budget tiers only work when packing stops at the first file that does not fit");
+                }
+                let mut outputs = vec![(output, max_size)];
+                outputs.extend(tiers);
+                let mut reports = pack_tiers(outputs, &options, &mut selected, entries)?;
+                let mut report = reports.remove(0);
+                report.tiers = reports;
+                return Ok(report);
+            }
+            Output::Volumes(..) if !tiers.is_empty() => {
+                bail!("Budget tiers can't be combined with volumes");
+            }
+            Output::Single(output) => {
+                let size =
+                    write_inputs(output, max_size, &options, &mut selected, &mut entries, None)?;
+                let volumes = Vec::new();
+                return Ok(Report {
+                    entries,
+                    size,
+                    groups: Vec::new(),
+                    volumes,
+                    tiers: Vec::new(),
+                });
+            }
+            Output::Volumes(volumes, create) => (volumes, create),
+        };
+        if volumes == 0 {
+            bail!("Need at least one volume");
+        }
+
+        /* Each volume is tried with what did not fit in the ones before.
+         * Only the last volume stores part of an input, it would fit whole
+         * in the next one. A volume is only created once something is
+         * stored in it, as an input that does not fit even in an empty
+         * volume is dropped instead. */
+        let included = |e: &EntryReport| matches!(e.status, EntryStatus::Included { .. });
+        let mut done = Vec::new();
+        let mut sizes = Vec::new();
+        while !entries.is_empty() && sizes.len() < volumes {
+            let volume = sizes.len() + 1;
+            options.note(format_args!("Volume {}:", volume));
+            let create = &mut create;
+            let output = Box::new(LazyFileSink::new(move || {
+                create(volume).map_err(|e| std::io::Error::other(format!("{:#}", e)))
+            }));
+            let options = if volume < volumes {
+                Options { partial: None, ..options.clone() }
+            } else {
+                options.clone()
+            };
+            let size = write_inputs(
+                output,
+                max_size,
+                &options,
+                &mut selected,
+                &mut entries,
+                Some(volume),
+            )?;
+
+            if !entries.iter().any(included) {
+                /* Packing stopped at an input that does not fit even in
+                 * an empty volume, try the ones after it */
+                let failed = entries.iter().position(|e| {
+                    matches!(
+                        e.status,
+                        EntryStatus::Skipped(SkipReason::DoesNotFit | SkipReason::ScreenedOut)
+                    )
+                });
+                match failed {
+                    Some(i) if !options.skip_oversized => {
+                        drop(selected.remove(i));
+                        done.push(entries.remove(i));
+                        continue;
+                    }
+                    _ => break,
+                }
+            }
+            sizes.push(size);
+
+            let (mut rest_inputs, mut rest) = (Vec::new(), Vec::new());
+            for (input, mut entry) in selected.drain(..).zip(entries.drain(..)) {
+                if included(&entry) {
+                    entry.volume = Some(volume);
+                    done.push(entry);
+                } else {
+                    rest_inputs.push(input);
+                    rest.push(entry);
+                }
+            }
+            (selected, entries) = (rest_inputs, rest);
+        }
+        done.append(&mut entries);
+
+        let size = sizes.iter().sum();
+        Ok(Report { entries: done, size, groups: Vec::new(), volumes: sizes, tiers: Vec::new() })
+    }
+}
+
+/// Contents of the inputs to time compressing, up to this much.
+const SAMPLE_LEN: u64 = 1024 * 1024;
+
+/// Taken from the beginning of each input in turn, up to this much.
+const SAMPLE_PER_INPUT: u64 = 64 * 1024;
+
+/// Samples spread over an input to screen, see [`Options::prescreen`].
+const SCREEN_CHUNKS: u64 = 4;
+
+/// Length of each sample to screen.
+const SCREEN_CHUNK_LEN: u64 = 64 * 1024;
+
+/// Part of the screening estimate that must fit for an input to be
+/// compressed. Higher qualities rarely get below half of what quality 1
+/// compresses to.
+const SCREEN_MARGIN: f64 = 0.5;
+
+/// Compressed size of the `size` bytes of contents `open_at` reads from
+/// an offset on, estimated from compressing samples spread over them
+/// quickly.
+pub(crate) fn screen_contents<'r>(
+    options: &Options,
+    size: u64,
+    open_at: impl Fn(u64) -> Result<Box<dyn Read + 'r>>,
+) -> Result<u64> {
+    let mut sample = Vec::new();
+    if size <= SCREEN_CHUNKS * SCREEN_CHUNK_LEN {
+        open_at(0)?.read_to_end(&mut sample).context("Could not read input")?;
+    } else {
+        for i in 0..SCREEN_CHUNKS {
+            open_at(size / SCREEN_CHUNKS * i)?
+                .take(SCREEN_CHUNK_LEN)
+                .read_to_end(&mut sample)
+                .context("Could not read input")?;
+        }
+    }
+    let ratio = match options.format {
+        Format::Uncompressed => 1.0,
+        _ => quick_ratio(&sample)?,
+    };
+    Ok((size as f64 * ratio) as u64)
+}
+
+/// Has the (regular) files among `inputs` read in the background, in
+/// order.
+fn prepare(options: &Options, inputs: &mut [Input]) {
+    let path = |input: &Input| match &input.source {
+        Source::Path(path) if decompresses(options, path) || path.is_file() => Some(path.clone()),
+        _ => None,
+    };
+    let files: Vec<_> = inputs.iter_mut().filter(|input| path(input).is_some()).collect();
+    let paths = files.iter().filter_map(|input| path(input)).collect();
+    for (input, pending) in files.into_iter().zip(prepare_ahead(options, paths)) {
+        input.source = Source::Pending(pending);
+    }
+}
+
+/// `options` with the brotli quality chosen for `inputs`, see
+/// [`Options::max_time`] and [`Options::auto_quality`].
+fn tuned(
+    max_size: u64,
+    options: Options,
+    inputs: &[Input],
+    entries: &[EntryReport],
+) -> Result<Options> {
+    if options.format != Format::Brotli {
+        bail!("Only the brotli quality can be chosen automatically");
+    }
+
+    let mut sample = Vec::new();
+    for input in inputs {
+        let len = std::cmp::min(SAMPLE_PER_INPUT, SAMPLE_LEN - sample.len() as u64);
+        input
+            .open_at(&options, 0)?
+            .take(len)
+            .read_to_end(&mut sample)
+            .context("Could not read input")?;
+        if sample.len() as u64 == SAMPLE_LEN {
+            break;
+        }
+    }
+
+    let sizes = inputs
+        .iter()
+        .zip(entries.iter())
+        .map(|(input, entry)| input.projected_len(&options, entry))
+        .collect::<Result<Vec<_>>>()?;
+    let (capacity, _) = capacity(max_size, &options, entries)?;
+    let quality = choose_quality(&options, &sample, &sizes, capacity)?;
+    options.note(format_args!("Compressing at quality {}.", quality));
+
+    Ok(Options { brotli: BrotliParams { quality, ..options.brotli }, ..options })
+}
+
+/// Writes as many of `selected`, in the order they are tried, to
+/// `output` as fit, as `volume` if given. Returns the size of the
+/// archive.
+fn write_inputs<'a>(
+    output: Box<dyn Sink + '_>,
+    max_size: u64,
+    options: &Options,
+    selected: &mut Vec<Input<'a>>,
+    entries: &mut Vec<EntryReport>,
+    volume: Option<usize>,
+) -> Result<u64> {
+    let all = selected.len();
+    let passes = options.optimize.is_some() || options.write_order.is_some();
+    if !passes {
+        return pack(output, max_size, options, selected, entries, all, volume);
+    }
+
+    /* Only the pass that writes the archive stores partial inputs */
+    let sizing = Options { partial: None, ..options.clone() };
+
+    /* Either the estimates or a sizing pass trying the inputs in
+     * `order` decide what to write. Those inputs are moved to the
+     * front, best first. */
+    let included = |e: &EntryReport| matches!(e.status, EntryStatus::Included { .. });
+    let wanted = match options.optimize {
+        Some(objective) => choose(max_size, options, objective, selected, entries)?,
+        None => {
+            let sink = Box::new(NullSink::default());
+            pack(sink, max_size, &sizing, selected, entries, all, volume)?;
+            entries.iter().map(included).collect()
+        }
+    };
+    let mut order: Vec<_> = (0..all).collect();
+    order.sort_by_key(|i| !wanted[*i]);
+    reorder(selected, &order);
+    reorder(entries, &order);
+    let mut chosen = wanted.iter().filter(|w| **w).count();
+
+    /* What is left out is not read again, unless by the next volume */
+    if volume.is_none() {
+        for input in &mut selected[chosen..] {
+            input.release();
+        }
+    }
+
+    let Some(write_order) = options.write_order else {
+        return pack(output, max_size, options, selected, entries, chosen, volume);
+    };
+
+    /* Written in `write_order` the chosen inputs may compress worse.
+     * Then the least wanted of them is left out, until the rest is
+     * known to fit before anything is written to `output`. */
+    let trial = Options { progress: None, skip_oversized: false, ..sizing };
+    loop {
+        let mut order: Vec<_> = (0..chosen).collect();
+        order.sort_by(|a, b| compare(write_order, &entries[*a], &entries[*b]));
+        order.extend(chosen..all);
+        reorder(selected, &order);
+        reorder(entries, &order);
+
+        let sink = Box::new(NullSink::default());
+        pack(sink, max_size, &trial, selected, entries, chosen, volume)?;
+        if entries[..chosen].iter().all(included) {
+            return pack(output, max_size, options, selected, entries, chosen, volume);
+        }
+
+        let mut inverse = vec![0; all];
+        for (i, j) in order.into_iter().enumerate() {
+            inverse[j] = i;
+        }
+        reorder(selected, &inverse);
+        reorder(entries, &inverse);
+
+        chosen -= 1;
+        entries[chosen].status = EntryStatus::Skipped(SkipReason::DoesNotFit);
+        if volume.is_none() {
+            selected[chosen].release();
+        }
+        options.note(format_args!(
+            "{} does not fit in write order.",
+            entries[chosen].label().display()
+        ));
+    }
+}
+
+/// Estimates the compressed size of every input and picks those to
+/// write, the others are marked [`SkipReason::NotSelected`].
+fn choose(
+    max_size: u64,
+    options: &Options,
+    objective: Objective,
+    inputs: &mut [Input],
+    entries: &mut [EntryReport],
+) -> Result<Vec<bool>> {
+    let quiet = Options { progress: None, ..options.clone() };
+    for (input, entry) in inputs.iter_mut().zip(entries.iter_mut()) {
+        /* Inputs left for another volume keep their estimates */
+        if entry.estimated_size.is_some() {
+            continue;
+        }
+        let estimate = input.estimate(&quiet, entry)?;
+        options.note(format_args!("{} (estimated {} bytes)", entry.label().display(), estimate));
+        entry.estimated_size = Some(estimate);
+    }
+
+    /* An input takes its room in the manifest as well. Less what the
+     * inputs that must be included take. */
+    let (capacity, bound) = capacity(max_size, options, entries)?;
+    let taken = |(i, entry): (usize, &EntryReport)| {
+        entry.estimated_size.expect("estimated above") + bound.growth(i) as u64
+    };
+    let must = |entry: &EntryReport| entry.priority == Priority::Must;
+    let required: u64 = entries.iter().enumerate().filter(|(_, e)| must(e)).map(taken).sum();
+    let capacity = capacity.saturating_sub(required);
+
+    /* A priority step is worth more than any ranks together, so that
+     * ranks only break ties */
+    let n = entries.len() as u64;
+    let step = n * (n + 1) / 2 + 1;
+    let weight = |entry: &EntryReport| match entry.priority {
+        Priority::Weight(weight) => weight,
+        Priority::Must => 0,
+    };
+    let lowest = entries.iter().filter(|e| !must(e)).map(weight).min().unwrap_or(0);
+    let items: Vec<_> = (0..n)
+        .zip(entries.iter())
+        .map(|(rank, entry)| {
+            let value = match objective {
+                _ if must(entry) => 0,
+                Objective::Count => 1,
+                Objective::Bytes => entry.decompressed_size.unwrap_or(entry.size),
+                Objective::Priority => {
+                    let above = weight(entry).abs_diff(lowest) + 1;
+                    above.saturating_mul(step).saturating_add(n - rank)
+                }
+            };
+            (taken((rank as usize, entry)), value)
+        })
+        .collect();
+
+    let mut wanted = knapsack(capacity, &items);
+    for (entry, wanted) in entries.iter_mut().zip(wanted.iter_mut()) {
+        *wanted |= must(entry);
+        if !*wanted {
+            entry.status = EntryStatus::Skipped(SkipReason::NotSelected);
+        }
+    }
+
+    Ok(wanted)
+}
+
+/// Lengths tried by how the input compressed when storing part of it,
+/// shorter ones are halves of the last.
+const PARTIAL_TRIES: usize = 2;
+
+/// Part of what seems to fit that is tried, so that the first try
+/// usually fits.
+const PARTIAL_MARGIN: f64 = 0.9;
+
+/// Stores about as much of an input that does not fit as fits in `room`
+/// bytes, cut as `cut` says, going by it compressing to `ratio` bytes
+/// per byte. The archive must be at the checkpoint before the input.
+/// Each length tried that does not fit is taken back again, see
+/// [`rewind`]. Returns the compressed size when something was stored.
+fn add_partial(
+    options: &Options,
+    archive: &mut tar::Builder<CheckpointWriter>,
+    input: &Input,
+    entry: &mut EntryReport,
+    cut: Cut,
+    room: u64,
+    ratio: f64,
+) -> Result<Option<u64>> {
+    let size = entry.decompressed_size.unwrap_or(entry.size);
+    let mut name = OsString::from(entry.name.clone());
+    name.push(".partial");
+    let name = PathBuf::from(name);
+
+    let before_pos = archive.get_mut().checkpoint()?;
+    let mut len = std::cmp::min((room as f64 / ratio * PARTIAL_MARGIN) as u64, size);
+    let mut tries = 0;
+    loop {
+        let part = input.part(options, &entry.name, size, len, cut)?;
+        if part.kept.is_empty() {
+            return Ok(None);
+        }
+
+        let (sha256, added) = match input.add_part(options, archive, &name, &part) {
+            Ok(added) => (added.sha256, Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        let (after_pos, _) = position_after(archive, added)?;
+        let compressed_size = after_pos - before_pos;
+        if compressed_size <= room {
+            options.note(format_args!(
+                "{} stored partially, {} of {} bytes (used {} bytes)",
+                entry.label().display(),
+                part.len(),
+                size,
+                compressed_size
+            ));
+            entry.name = name;
+            entry.partial = Some(part);
+            entry.sha256 = sha256;
+            entry.status = EntryStatus::Included { compressed_size };
+            return Ok(Some(compressed_size));
+        }
+
+        /* Try again by how this part compressed, but shorter in any
+         * case, and once that missed too with half as much each time */
+        rewind(options, archive)?;
+        tries += 1;
+        len = if tries < PARTIAL_TRIES {
+            let ratio = compressed_size as f64 / part.len() as f64;
+            std::cmp::min((room as f64 / ratio * PARTIAL_MARGIN) as u64, part.len() - 1)
+        } else {
+            part.len() / 2
+        };
+    }
+}
+
+/// Whether [`Options::prescreen`] passes over the input of `entry`
+/// without compressing it, as it can't fit in `room` bytes. Records the
+/// estimate in `entry`.
+fn hopeless(
+    options: &Options,
+    input: &mut Input,
+    entry: &mut EntryReport,
+    room: u64,
+) -> Result<bool> {
+    /* An input that fits as it is is never hopeless, neither do `.gz`
+     * files that fit compressed as they are */
+    if !options.prescreen || entry.priority == Priority::Must || entry.size <= room {
+        return Ok(false);
+    }
+    /* Measured, sampled and then stored from one decompressed copy */
+    input.decompress_once(options);
+    let size = input.stored_len(options, entry)?;
+    let Some(estimate) = input.screen(options, size)? else {
+        return Ok(false);
+    };
+    entry.screened_size = Some(estimate);
+    let hopeless = (estimate as f64 * SCREEN_MARGIN) as u64 > room;
+    options.note(format_args!(
+        "{} estimated at {} bytes with {} bytes left{}",
+        entry.label().display(),
+        estimate,
+        room,
+        if hopeless { ", screened out." } else { "." }
+    ));
+    Ok(hopeless)
+}
+
+/// Spooled archives this large are worth a note when rewound.
+const LARGE_SPOOL: u64 = 64 * 1024 * 1024;
+
+/// Throws away what was appended to `archive` since the checkpoint, by
+/// recompressing all that was written before it.
+fn rewind(options: &Options, archive: &mut tar::Builder<CheckpointWriter>) -> Result<()> {
+    let spooled = archive.get_ref().spooled();
+    if spooled >= LARGE_SPOOL {
+        options.note(format_args!(
+            "Recompressing {} bytes written so far to take a file back.",
+            spooled
+        ));
+    }
+    archive.get_mut().rewind()
+}
+
+/// Position of `archive` after appending to it with the outcome `added`,
+/// and whether that was abandoned for going over the archive's limit.
+/// Then the position is that reached by then, already over the limit.
+fn position_after(
+    archive: &mut tar::Builder<CheckpointWriter>,
+    added: Result<()>,
+) -> Result<(u64, bool)> {
+    match added {
+        Ok(()) => Ok((flush_and_get_position(archive)?, false)),
+        Err(e) if is_over_limit(&e) => Ok((archive.get_mut().position(), true)),
+        Err(e) => Err(e),
+    }
+}
+
+/// What is left of `max_size` for the inputs once the manifest has room,
+/// like in [`pack`], and the bound of the manifest's length to go with
+/// it. Each input included takes up to [`ManifestBound::growth`] more.
+fn capacity(
+    max_size: u64,
+    options: &Options,
+    entries: &[EntryReport],
+) -> Result<(u64, ManifestBound)> {
+    let mut archive =
+        CheckpointWriter::new(Box::new(NullSink::default()), Compression::of(options), false)?;
+    let mut manifest = Manifest::new(max_size, archive.parameters(), options);
+    let bound = manifest.bound(entries);
+    let reserve = archive.tail_size(tar_entry_size(bound.len() as u64));
+    Ok((max_size.saturating_sub(archive.checkpoint()? + reserve), bound))
+}
+
+/// Moves the item at `order[i]` to `i`.
+fn reorder<T>(items: &mut Vec<T>, order: &[usize]) {
+    let mut old: Vec<_> = items.drain(..).map(Some).collect();
+    items.extend(order.iter().map(|i| old[*i].take().expect("order is a permutation")));
+}
+
+/// Writes the first `tried` of `inputs` to `output`, as many as fit, and
+/// records in `entries` what happened to them. The manifest lists all
+/// of `entries`. Returns the size of the archive.
+fn pack<'a>(
+    output: Box<dyn Sink + '_>,
+    max_size: u64,
+    options: &Options,
+    inputs: &mut [Input<'a>],
+    entries: &mut [EntryReport],
+    tried: usize,
+    volume: Option<usize>,
+) -> Result<u64> {
+    for entry in &mut entries[..tried] {
+        entry.status = EntryStatus::Skipped(SkipReason::NotAttempted);
+    }
+
+    /* Inputs over their group's quota are taken back again, and so are
+     * the attempts to store part of an input */
+    let rewindable =
+        options.skip_oversized || !options.groups.is_empty() || options.partial.is_some();
+    let mut truncate = false;
+    let mut archive =
+        tar::Builder::new(CheckpointWriter::new(output, Compression::of(options), rewindable)?);
+
+    /* Don't need irrelevant details like timestamp and owner/group */
+    archive.mode(tar::HeaderMode::Deterministic);
+
+    /* The manifest is written last, once it is known what fits.
+     * Room for it is kept free all along, enough to store it
+     * uncompressed after any checkpoint. That room shrinks as what
+     * happens to the inputs is known. */
+    let mut manifest = Manifest::new(max_size, archive.get_ref().parameters(), options);
+    if let Some(volume) = volume {
+        manifest.set_volume(volume);
+    }
+    let mut bound = manifest.bound(entries);
+    for (i, entry) in entries.iter().enumerate().skip(tried) {
+        bound.settle(i, entry);
+    }
+    let reserve = archive.get_ref().tail_size(tar_entry_size(bound.len() as u64));
+
+    let start_pos = archive.get_mut().checkpoint()?;
+    if start_pos + reserve > max_size {
+        bail!("A budget of {} bytes can't even hold the manifest", max_size);
+    }
+
+    let capacity = max_size - start_pos - reserve;
+    let quotas: Vec<_> = options.groups.iter().map(|g| g.share.of(capacity)).collect();
+    manifest.set_quotas(&quotas);
+    let mut used_by_group = vec![0; quotas.len()];
+    let group = |entry: &EntryReport| {
+        options.groups.iter().position(|g| entry.group.as_ref() == Some(&g.name))
+    };
+
+    /* A first round keeps to the quotas, a second one hands what groups
+     * left unused to the inputs that were over their quota */
+    /* Decompressed copies are deleted once an input is settled, unless
+     * another pass or volume reads it again */
+    let once = options.optimize.is_none() && options.write_order.is_none();
+    let skipped_once = once && volume.is_none();
+
+    let mut queue: Vec<_> = (0..tried).collect();
+    let mut limited = !quotas.is_empty();
+    'rounds: loop {
+        let mut deferred = Vec::new();
+        for i in queue {
+            let (input, entry) = (&mut inputs[i], &mut entries[i]);
+            let before_pos = archive.get_mut().checkpoint()?;
+
+            /* An input is abandoned as soon as it is known not to fit */
+            let reserve = archive.get_ref().tail_size(tar_entry_size(bound.trying(i) as u64));
+            let limit = max_size.saturating_sub(reserve);
+            archive.get_mut().set_limit(Some(limit));
+
+            let room = limit.saturating_sub(before_pos);
+            if hopeless(options, input, entry, room)? {
+                entry.status = EntryStatus::Skipped(SkipReason::ScreenedOut);
+                if let Some(cut) = options.partial {
+                    let screened = entry.screened_size.expect("screened above");
+                    let size = entry.decompressed_size.unwrap_or(entry.size);
+                    let ratio = screened as f64 / size as f64;
+                    if let Some(size) =
+                        add_partial(options, &mut archive, input, entry, cut, room, ratio)?
+                    {
+                        if let Some(g) = group(entry) {
+                            used_by_group[g] += size;
+                        }
+                    }
+                }
+                bound.settle(i, entry);
+                if options.skip_oversized {
+                    if skipped_once {
+                        input.release();
+                    }
+                    continue;
+                }
+                break 'rounds;
+            }
+
+            let added = input.add_to(options, &mut archive, entry);
+            let (after_pos, abandoned) = position_after(&mut archive, added)?;
+            if after_pos + reserve > max_size {
+                if abandoned {
+                    options.note(format_args!(
+                        "{} does not fit. Abandoned once the archive reached {} bytes.",
+                        entry.label().display(),
+                        after_pos
+                    ));
+                } else {
+                    options.note(format_args!(
+                        "{} does not fit. Archive would be {} bytes.",
+                        entry.label().display(),
+                        after_pos
+                    ));
+                }
+                entry.status = EntryStatus::Skipped(SkipReason::DoesNotFit);
+                if let Some(cut) = options.partial {
+                    let written = archive.get_ref().since_checkpoint();
+                    let ratio = (after_pos - before_pos) as f64 / written.max(1) as f64;
+                    rewind(options, &mut archive)?;
+                    let room = limit.saturating_sub(before_pos);
+                    if let Some(size) =
+                        add_partial(options, &mut archive, input, entry, cut, room, ratio)?
+                    {
+                        if let Some(g) = group(entry) {
+                            used_by_group[g] += size;
+                        }
+                    }
+                    bound.settle(i, entry);
+                    if options.skip_oversized {
+                        if skipped_once {
+                            input.release();
+                        }
+                        continue;
+                    }
+                    break 'rounds;
+                }
+                bound.settle(i, entry);
+                if options.skip_oversized {
+                    rewind(options, &mut archive)?;
+                    if skipped_once {
+                        input.release();
+                    }
+                    continue;
+                }
+                truncate = true;
+                break 'rounds;
+            }
+            let size = after_pos - before_pos;
+            if let Some(g) =
+                group(entry).filter(|g| limited && used_by_group[*g] + size > quotas[*g])
+            {
+                options.note(format_args!(
+                    "{} is over the quota of {}, deferred.",
+                    entry.label().display(),
+                    options.groups[g].name
+                ));
+                rewind(options, &mut archive)?;
+                deferred.push(i);
+                continue;
+            }
+            options.note(format_args!("{} (used {} bytes)", entry.label().display(), size));
+            entry.status = EntryStatus::Included { compressed_size: size };
+            bound.settle(i, entry);
+            if once {
+                input.release();
+            }
+            if let Some(g) = group(entry) {
+                used_by_group[g] += size;
+            }
+            if after_pos + reserve == max_size {
+                break 'rounds;
+            }
+        }
+
+        if !limited || deferred.is_empty() {
+            break;
+        }
+        limited = false;
+        queue = deferred;
+    }
+
+    /* The inputs after where packing stopped are not read either */
+    if skipped_once {
+        for input in &mut inputs[..tried] {
+            input.release();
+        }
+    }
+
+    /* A volume that stores nothing is not written at all */
+    let included = |e: &EntryReport| matches!(e.status, EntryStatus::Included { .. });
+    if volume.is_some() && !entries[..tried].iter().any(included) {
+        return Ok(0);
+    }
+
+    archive.get_mut().set_limit(None);
+    let manifest = manifest.to_json(entries);
+    let tail = manifest_entry(&manifest)?;
+
+    let size = if truncate {
+        /* Need to rewind (truncate) the archive to fit max-size. */
+        archive.into_inner().context("Could not finish archive")?.truncate_and_close(&tail)?
+    } else {
+        archive.get_mut().checkpoint()?;
+        add_manifest(&manifest, &mut archive)?;
+        archive.into_inner().context("Could not finish archive")?.finish(max_size, &tail)?
+    };
+
+    Ok(size)
+}
+
+/// Writes `inputs` to all of `outputs` at once, each cut back at the
+/// first input that does not fit its budget, and reports on each.
+fn pack_tiers<'a>(
+    outputs: Vec<(Box<dyn Sink + 'a>, u64)>,
+    options: &Options,
+    inputs: &mut [Input<'a>],
+    mut entries: Vec<EntryReport>,
+) -> Result<Vec<Report>> {
+    let (sinks, budgets): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+    let (tee, sinks) = TeeSink::new(sinks);
+    let mut archive =
+        tar::Builder::new(CheckpointWriter::new(Box::new(tee), Compression::of(options), false)?);
+    archive.mode(tar::HeaderMode::Deterministic);
+
+    /* Each output gets its own manifest, stored uncompressed once the
+     * output is complete */
+    let mut manifests: Vec<_> = budgets
+        .iter()
+        .map(|max_size| Manifest::new(*max_size, archive.get_ref().parameters(), options))
+        .collect();
+    let mut bounds: Vec<_> = manifests.iter_mut().map(|m| m.bound(&entries)).collect();
+    let reserve = |archive: &tar::Builder<CheckpointWriter>, len: usize| {
+        archive.get_ref().tail_size(tar_entry_size(len as u64))
+    };
+
+    let start_pos = archive.get_mut().checkpoint()?;
+    for (max_size, bound) in budgets.iter().zip(&bounds) {
+        if start_pos + reserve(&archive, bound.len()) > *max_size {
+            bail!("A budget of {} bytes can't even hold the manifest", max_size);
+        }
+    }
+
+    let mut reports: Vec<Option<Report>> = vec![None; budgets.len()];
+    let mut close =
+        |k: usize, archive: &tar::Builder<CheckpointWriter>, pos, entries: &[EntryReport]| {
+            let tail = manifest_entry(&manifests[k].to_json(entries))?;
+            let end = archive.get_ref().end_at_checkpoint(&tail);
+            let mut sink = sinks.borrow_mut()[k].take().expect("closed once");
+            sink.rollback().context("Could not truncate output")?;
+            sink.write_all(&end).context("Could not write end of archive")?;
+            sink.commit().context("Could not write end of archive")?;
+            let size = pos + end.len() as u64;
+            reports[k] = Some(Report {
+                entries: entries.to_vec(),
+                size,
+                groups: Vec::new(),
+                volumes: Vec::new(),
+                tiers: Vec::new(),
+            });
+            anyhow::Ok(())
+        };
+
+    let mut open: Vec<_> = (0..budgets.len()).collect();
+    for i in 0..inputs.len() {
+        let before_pos = archive.get_mut().checkpoint()?;
+        let reserves: Vec<_> = bounds.iter().map(|b| reserve(&archive, b.trying(i))).collect();
+        /* Abandoned as soon as it does not fit any budget */
+        let limit = open.iter().map(|k| budgets[*k].saturating_sub(reserves[*k])).max();
+        archive.get_mut().set_limit(limit);
+        let room = limit.expect("some output is open").saturating_sub(before_pos);
+        if hopeless(options, &mut inputs[i], &mut entries[i], room)? {
+            entries[i].status = EntryStatus::Skipped(SkipReason::ScreenedOut);
+            for k in open.drain(..) {
+                close(k, &archive, before_pos, &entries)?;
+            }
+            break;
+        }
+        let added = inputs[i].add_to(options, &mut archive, &mut entries[i]);
+        let (after_pos, _) = position_after(&mut archive, added)?;
+        entries[i].status = EntryStatus::Included { compressed_size: after_pos - before_pos };
+
+        let (full, fits): (Vec<_>, Vec<_>) =
+            open.into_iter().partition(|k| after_pos + reserves[*k] > budgets[*k]);
+        if !full.is_empty() {
+            let mut cut_back = entries.clone();
+            cut_back[i].status = EntryStatus::Skipped(SkipReason::DoesNotFit);
+            for k in full {
+                options.note(format_args!(
+                    "{} does not fit in {} bytes.",
+                    entries[i].label().display(),
+                    budgets[k]
+                ));
+                close(k, &archive, before_pos, &cut_back)?;
+            }
+        } else {
+            let size = after_pos - before_pos;
+            options.note(format_args!("{} (used {} bytes)", entries[i].label().display(), size));
+        }
+        inputs[i].release();
+        for bound in &mut bounds {
+            bound.settle(i, &entries[i]);
+        }
+        open = fits;
+        if open.is_empty() {
+            break;
+        }
+    }
+
+    archive.get_mut().set_limit(None);
+    let pos = archive.get_mut().checkpoint()?;
+    for k in open {
+        close(k, &archive, pos, &entries)?;
+    }
+
+    Ok(reports.into_iter().map(|r| r.expect("all closed")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::MANIFEST_NAME;
+    use crate::testutil::{decompress, noise, read_archive};
+
+    fn build(options: Options, max_size: u64, sizes: &[usize]) -> (Report, File) {
+        let output = tempfile::tempfile().unwrap();
+        let mut builder =
+            PartialArchiveBuilder::new(output.try_clone().unwrap(), max_size, options);
+        for (i, size) in sizes.iter().enumerate() {
+            builder.add_data(format!("file{}", i), noise(*size, i as u64));
+        }
+        (builder.finish().unwrap(), output)
+    }
+
+    fn names(archive: &[(PathBuf, Vec<u8>)]) -> Vec<String> {
+        archive.iter().map(|(name, _)| name.display().to_string()).collect()
+    }
+
+    #[test]
+    fn test_stops_at_first_oversize() {
+        let (report, output) = build(Options::default(), 10000, &[3000, 8000, 1000]);
+
+        let statuses: Vec<_> = report.entries.iter().map(|e| e.status.clone()).collect();
+        assert!(matches!(statuses[0], EntryStatus::Included { .. }));
+        assert_eq!(statuses[1], EntryStatus::Skipped(SkipReason::DoesNotFit));
+        assert_eq!(statuses[2], EntryStatus::Skipped(SkipReason::NotAttempted));
+
+        assert!(report.size <= 10000);
+        assert_eq!(report.size, output.metadata().unwrap().len());
+        let archive = read_archive(&output);
+        assert_eq!(names(&archive), ["file0", MANIFEST_NAME]);
+        let manifest: serde_json::Value = serde_json::from_slice(&archive[1].1).unwrap();
+        assert_eq!(manifest["included"][0]["name"], "file0");
+        assert_eq!(manifest["included"][0]["size"], 3000);
+        assert_eq!(manifest["skipped"][0]["name"], "file1");
+        assert_eq!(manifest["skipped"][0]["reason"], "does-not-fit");
+        assert_eq!(manifest["skipped"][1]["reason"], "not-attempted");
+
+        let tar = decompress(&output);
+        assert!(tar.ends_with(&[0; 1024]));
+    }
+
+    #[test]
+    fn test_streaming_matches_file() {
+        for skip_oversized in [false, true] {
+            let options = Options { skip_oversized, ..Default::default() };
+            let sizes = [3000, 8000, 1000, 7000, 2000];
+            let (file_report, output) = build(options.clone(), 10000, &sizes);
+
+            let mut streamed = Vec::new();
+            let mut builder = PartialArchiveBuilder::new_streaming(&mut streamed, 10000, options);
+            for (i, size) in sizes.iter().enumerate() {
+                builder.add_data(format!("file{}", i), noise(*size, i as u64));
+            }
+            let report = builder.finish().unwrap();
+
+            assert_eq!(report.size, streamed.len() as u64);
+            assert!(report.size <= 10000);
+            let statuses =
+                |r: &Report| r.entries.iter().map(|e| e.status.clone()).collect::<Vec<_>>();
+            assert_eq!(statuses(&report), statuses(&file_report));
+
+            /* Only the manifest's creation time may differ */
+            let archive = read_archive(std::io::Cursor::new(&streamed));
+            let expected = read_archive(&output);
+            assert_eq!(archive.len(), expected.len());
+            assert_eq!(archive[..archive.len() - 1], expected[..expected.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn test_other_formats() {
+        /* Plain tar needs more room for the headers and padding */
+        for (format, format_name, budget) in [
+            (Format::Zstd, "zstd", 10000),
+            (Format::Gzip, "gzip", 10000),
+            (Format::Uncompressed, "none", 14000),
+        ] {
+            for (skip_oversized, expected) in
+                [(false, &["file0"][..]), (true, &["file0", "file2", "file4"][..])]
+            {
+                let options = Options { skip_oversized, format, ..Default::default() };
+                let (report, output) = build(options, budget, &[3000, 8000, 1000, 7000, 2000]);
+
+                assert!(report.size <= budget);
+                let archive = read_archive(&output);
+                let mut names = names(&archive);
+                assert_eq!(names.pop().unwrap(), MANIFEST_NAME);
+                assert_eq!(names, expected);
+                let manifest: serde_json::Value =
+                    serde_json::from_slice(&archive.last().unwrap().1).unwrap();
+                assert_eq!(manifest["compression"]["format"], format_name);
+                assert!(decompress(&output).ends_with(&[0; 1024]));
+            }
+
+            /* Everything fits, finished by the encoder */
+            let options = Options { format, ..Default::default() };
+            let (report, output) = build(options.clone(), 100000, &[3000, 8000]);
+            assert_eq!(report.included().count(), 2);
+            assert_eq!(names(&read_archive(&output)), ["file0", "file1", MANIFEST_NAME]);
+
+            /* Nothing fits, a zstd encoder has not even written a frame
+             * header then */
+            let (report, output) = build(options, 3000, &[8000]);
+            assert_eq!(report.included().count(), 0);
+            assert_eq!(names(&read_archive(&output)), [MANIFEST_NAME]);
+        }
+    }
+
+    #[test]
+    fn test_brotli_params() {
+        for brotli in [
+            BrotliParams { quality: 5, lgwin: 16, large_window: false, buffer_size: 100 },
+            BrotliParams { quality: 11, lgwin: 26, large_window: true, buffer_size: 4096 },
+        ] {
+            let options = Options { brotli, skip_oversized: true, ..Default::default() };
+            let (report, output) = build(options, 10000, &[3000, 8000, 1000]);
+
+            assert!(report.size <= 10000);
+            let archive = read_archive(&output);
+            assert_eq!(names(&archive), ["file0", "file2", MANIFEST_NAME]);
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&archive.last().unwrap().1).unwrap();
+            assert_eq!(manifest["compression"]["quality"], brotli.quality);
+            assert_eq!(manifest["compression"]["lgwin"], brotli.lgwin);
+            assert_eq!(manifest["compression"]["large_window"], brotli.large_window);
+        }
+
+        for (quality, lgwin) in [(12, 22), (11, 9), (11, 25)] {
+            let brotli = BrotliParams { quality, lgwin, ..Default::default() };
+            let options = Options { brotli, ..Default::default() };
+            let mut builder =
+                PartialArchiveBuilder::new(tempfile::tempfile().unwrap(), 10000, options);
+            builder.add_data("file0", noise(100, 0));
+            assert!(builder.finish().is_err());
+        }
+    }
+
+    #[test]
+    fn test_quality_tuning() {
+        /* Nothing is that fast, so the fastest quality is taken */
+        let options = Options { max_time: Some(Duration::ZERO), ..Default::default() };
+        let (report, output) = build(options, 10000, &[3000, 1000]);
+        assert_eq!(report.included().count(), 2);
+        let archive = read_archive(&output);
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&archive.last().unwrap().1).unwrap();
+        assert_eq!(manifest["compression"]["quality"], 1);
+        assert_eq!(manifest["quality_chosen_by"]["max_time"], 0.0);
+
+        let options = Options { auto_quality: true, ..Default::default() };
+        let (report, output) = build(options, 10000, &[3000, 8000, 1000]);
+        assert!(report.size <= 10000);
+        let archive = read_archive(&output);
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&archive.last().unwrap().1).unwrap();
+        assert_eq!(manifest["quality_chosen_by"]["auto"], true);
+        assert!(manifest["compression"]["quality"].as_u64().unwrap() <= 11);
+
+        /* Projected by the decompressed size, even of inputs not tried,
+         * without decompressing them to tell */
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zeros.gz");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(&[0; 100000]).unwrap();
+        std::fs::write(&path, gz.finish().unwrap()).unwrap();
+        let options =
+            Options { auto_quality: true, auto_decompress_gz: true, ..Default::default() };
+        let mut builder =
+            PartialArchiveBuilder::new(tempfile::tempfile().unwrap(), 10000, options.clone());
+        builder.add_data("noise", noise(12000, 0));
+        builder.add_path(&path);
+        let report = builder.finish().unwrap();
+        assert_eq!(report.entries[1].status, EntryStatus::Skipped(SkipReason::NotAttempted));
+        assert_eq!(report.entries[1].decompressed_size, None);
+        let input = Input { name: None, source: Source::Path(path) };
+        let entry = input.report(&options).unwrap();
+        assert_eq!(input.projected_len(&options, &entry).unwrap(), 100000);
+
+        let options = Options { auto_quality: true, format: Format::Gzip, ..Default::default() };
+        let mut builder = PartialArchiveBuilder::new(tempfile::tempfile().unwrap(), 10000, options);
+        builder.add_data("file0", noise(100, 0));
+        assert!(builder.finish().is_err());
+    }
+
+    #[test]
+    fn test_abandons_oversized() {
+        struct Counting<'c>(std::io::Take<std::io::Repeat>, &'c std::cell::Cell<u64>);
+        impl Read for Counting<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.0.read(buf)?;
+                self.1.set(self.1.get() + n as u64);
+                Ok(n)
+            }
+        }
+
+        let huge = 1 << 30;
+        for skip_oversized in [false, true] {
+            let read = std::cell::Cell::new(0);
+            let options =
+                Options { format: Format::Uncompressed, skip_oversized, ..Default::default() };
+            let output = tempfile::tempfile().unwrap();
+            let mut builder =
+                PartialArchiveBuilder::new(output.try_clone().unwrap(), 100000, options);
+            builder.add_data("file0", noise(1000, 0));
+            builder.add_reader("huge", huge, Counting(std::io::repeat(7).take(huge), &read));
+            builder.add_data("file2", noise(1000, 2));
+            let report = builder.finish().unwrap();
+
+            assert!(read.get() < 1 << 20);
+            assert_eq!(report.entries[1].status, EntryStatus::Skipped(SkipReason::DoesNotFit));
+            let expected: &[_] = if skip_oversized {
+                &["file0", "file2", MANIFEST_NAME]
+            } else {
+                &["file0", MANIFEST_NAME]
+            };
+            assert_eq!(names(&read_archive(&output)), expected);
+        }
+    }
+
+    #[test]
+    fn test_prescreen() {
+        let options = Options { prescreen: true, skip_oversized: true, ..Default::default() };
+        let output = tempfile::tempfile().unwrap();
+        let mut builder = PartialArchiveBuilder::new(output.try_clone().unwrap(), 10000, options);
+        builder.add_data("noise", noise(40000, 0));
+        builder.add_data("zeros", vec![0; 100000]);
+        builder.add_data("small", noise(1000, 2));
+        let report = builder.finish().unwrap();
+
+        let screened = &report.entries[0];
+        assert_eq!(screened.status, EntryStatus::Skipped(SkipReason::ScreenedOut));
+        assert!(screened.screened_size.unwrap() > 30000);
+        assert!(screened.sha256.is_none());
+        /* Compresses well enough to be tried, and fits */
+        assert!(report.entries[1].screened_size.unwrap() < 1000);
+        assert!(matches!(report.entries[1].status, EntryStatus::Included { .. }));
+        assert_eq!(report.entries[2].screened_size, None);
+
+        let archive = read_archive(&output);
+        assert_eq!(names(&archive), ["zeros", "small", MANIFEST_NAME]);
+        let manifest: serde_json::Value = serde_json::from_slice(&archive[2].1).unwrap();
+        assert_eq!(manifest["skipped"][0]["reason"], "screened-out");
+        assert!(manifest["skipped"][0]["screened_size"].is_u64());
+
+        /* Screened by the decompressed size, about twice the compressed */
+        let hex: String = noise(30000, 3).iter().map(|b| format!("{:02x}", b)).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.hex.gz");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(hex.as_bytes()).unwrap();
+        std::fs::write(&path, gz.finish().unwrap()).unwrap();
+        let options = Options { prescreen: true, auto_decompress_gz: true, ..Default::default() };
+        let mut builder = PartialArchiveBuilder::new(tempfile::tempfile().unwrap(), 10000, options);
+        builder.add_path(&path);
+        let report = builder.finish().unwrap();
+        let entry = &report.entries[0];
+        assert_eq!(entry.status, EntryStatus::Skipped(SkipReason::ScreenedOut));
+        assert_eq!(entry.decompressed_size, Some(60000));
+        assert!(entry.screened_size.unwrap() > 20000, "{:?}", entry);
+    }
+
+    #[test]
+    fn test_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for i in 0..8 {
+            let data = noise(1500 + 500 * i, i as u64);
+            let path = dir.path().join(format!("file{}.gz", i));
+            let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            gz.write_all(&data).unwrap();
+            if i % 2 == 0 {
+                std::fs::write(&path, gz.finish().unwrap()).unwrap();
+            } else {
+                std::fs::write(path.with_extension("log"), data).unwrap();
+            }
+            paths.push(path);
+        }
+
+        let build = |jobs| {
+            let options = Options {
+                jobs,
+                auto_decompress_gz: true,
+                skip_oversized: true,
+                prescreen: true,
+                ..Default::default()
+            };
+            let output = tempfile::tempfile().unwrap();
+            let mut builder =
+                PartialArchiveBuilder::new(output.try_clone().unwrap(), 12000, options);
+            builder.add_path(dir.path());
+            (builder.finish().unwrap(), read_archive(&output))
+        };
+        let (expected_report, expected) = build(0);
+        let (report, archive) = build(3);
+
+        let sums = |r: &Report| r.entries.iter().map(|e| e.sha256.clone()).collect::<Vec<_>>();
+        assert_eq!(sums(&report), sums(&expected_report));
+        let screened = |r: &Report| r.entries.iter().map(|e| e.screened_size).collect::<Vec<_>>();
+        assert_eq!(screened(&report), screened(&expected_report));
+        assert!(report.entries.iter().any(|e| e.decompressed_size.is_some()));
+        assert!(report.skipped().count() > 0);
+        /* Only the manifest's creation time may differ */
+        assert_eq!(archive.len(), expected.len());
+        assert_eq!(archive[..archive.len() - 1], expected[..expected.len() - 1]);
+    }
+
+    #[test]
+    fn test_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for (name, data) in [
+            (".gitignore", "b.log\n"),
+            ("a.json", "{}"),
+            ("b.log", "log"),
+            ("sub/c.json", "[]"),
+            ("sub/d-private.json", "{}"),
+        ] {
+            std::fs::write(dir.path().join(name), data).unwrap();
+        }
+
+        let added = |options: Options| {
+            let output = tempfile::tempfile().unwrap();
+            let mut builder =
+                PartialArchiveBuilder::new(output.try_clone().unwrap(), 100000, options);
+            builder.add_path_with_name(dir.path(), "dumps");
+            let report = builder.finish().unwrap();
+            let mut archive = names(&read_archive(&output));
+            assert_eq!(archive.pop().unwrap(), MANIFEST_NAME);
+            assert_eq!(report.entries.len(), archive.len());
+            archive
+        };
+
+        assert_eq!(
+            added(Options { respect_ignore_files: true, ..Default::default() }),
+            ["dumps/.gitignore", "dumps/a.json", "dumps/sub/c.json", "dumps/sub/d-private.json"]
+        );
+        assert_eq!(
+            added(Options {
+                include: vec!["*.json".into(), "*.log".into()],
+                exclude: vec!["*-private.json".into()],
+                ..Default::default()
+            }),
+            ["dumps/a.json", "dumps/b.log", "dumps/sub/c.json"]
+        );
+    }
+
+    #[test]
+    fn test_budget_too_small_for_manifest() {
+        let output = tempfile::tempfile().unwrap();
+        let mut builder = PartialArchiveBuilder::new(output, 100, Options::default());
+        builder.add_data("file", "data");
+        assert!(builder.finish().is_err());
+    }
+
+    #[test]
+    fn test_many_small_inputs() {
+        let brotli = BrotliParams { quality: 1, ..Default::default() };
+        let options = Options { brotli, ..Default::default() };
+        let (report, output) = build(options.clone(), 800000, &[20; 3000]);
+        assert_eq!(report.included().count(), 3000);
+        assert!(report.size <= 800000);
+        assert_eq!(names(&read_archive(&output)).len(), 3001);
+
+        /* Listing the skipped ones would take more than half the budget */
+        let (report, output) = build(options, 200000, &[20; 3000]);
+        let included = report.included().count();
+        assert!(included > 500 && included < 3000);
+        assert!(report.size <= 200000);
+        let archive = read_archive(&output);
+        let manifest: serde_json::Value = serde_json::from_slice(&archive[included].1).unwrap();
+        assert_eq!(manifest["included"].as_array().unwrap().len(), included);
+        assert_eq!(manifest["skipped"], serde_json::json!([]));
+        assert_eq!(manifest["skipped_unlisted"]["count"], 3000 - included);
+    }
+
+    #[test]
+    fn test_skip_oversized() {
+        let options = Options { skip_oversized: true, ..Default::default() };
+        let (report, output) = build(options, 10000, &[3000, 8000, 1000, 7000, 2000]);
+
+        let included: Vec<_> = report.included().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(included, ["file0", "file2", "file4"]);
+        assert!(report.skipped().all(|e| e.status == EntryStatus::Skipped(SkipReason::DoesNotFit)));
+
+        assert!(report.size <= 10000);
+        let archive = read_archive(&output);
+        assert_eq!(names(&archive), ["file0", "file2", "file4", MANIFEST_NAME]);
+        assert_eq!(archive[1].1, noise(1000, 2));
+    }
+
+    #[test]
+    fn test_progress() {
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress = {
+            let lines = lines.clone();
+            Progress::new(move |line| lines.lock().unwrap().push(line.to_string()))
+        };
+        let options = Options { progress: Some(progress), ..Default::default() };
+        build(options, 10000, &[3000, 8000]);
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("file0 (used "));
+        assert!(lines[1].starts_with("file1 does not fit."));
+    }
+
+    #[test]
+    fn test_optimize() {
+        let options = Options { optimize: Some(Objective::Count), ..Default::default() };
+        let (report, output) = build(options, 10000, &[7000, 3000, 2500, 1000]);
+
+        let included: Vec<_> = report.included().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(included, ["file1", "file2", "file3"]);
+        assert_eq!(report.entries[3].status, EntryStatus::Skipped(SkipReason::NotSelected));
+        assert!(report.entries.iter().all(|e| e.estimated_size.unwrap() > e.size));
+
+        assert!(report.size <= 10000);
+        let archive = read_archive(&output);
+        assert_eq!(names(&archive), ["file1", "file2", "file3", MANIFEST_NAME]);
+        let manifest: serde_json::Value = serde_json::from_slice(&archive[3].1).unwrap();
+        assert!(manifest["included"][0]["estimated_size"].is_u64());
+        assert!(manifest["included"][0]["compressed_size"].is_u64());
+        assert_eq!(manifest["skipped"][0]["reason"], "not-selected");
+
+        let options = Options { optimize: Some(Objective::Bytes), ..Default::default() };
+        let (report, _) = build(options, 10000, &[7000, 3000, 2500, 1000]);
+        let included: Vec<_> = report.included().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(included, ["file0", "file3"]);
+
+        /* One input of priority 3 outweighs three of priority 0, the
+         * smallest may just fit next to it */
+        let options = Options {
+            optimize: Some(Objective::Priority),
+            priorities: vec![("file0".into(), Priority::Weight(3))],
+            ..Default::default()
+        };
+        let (report, _) = build(options, 10000, &[7000, 3000, 2500, 1000]);
+        let included: Vec<_> = report.included().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(included[0], "file0");
+        assert!(!included.iter().any(|name| name == "file1" || name == "file2"));
+    }
+
+    #[test]
+    fn test_priorities() {
+        let priorities =
+            vec![("file2".into(), Priority::Must), ("file0".into(), Priority::Weight(-1))];
+        let options = Options { priorities, skip_oversized: true, ..Default::default() };
+        let (report, output) = build(options, 10000, &[3000, 8000, 1000, 2000]);
+
+        let names_tried: Vec<_> =
+            report.entries.iter().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(names_tried, ["file2", "file1", "file3", "file0"]);
+        assert_eq!(names(&read_archive(&output)), ["file2", "file3", "file0", MANIFEST_NAME]);
+
+        let priorities = vec![("file1".into(), Priority::Must)];
+        let options = Options { priorities, ..Default::default() };
+        let mut builder = PartialArchiveBuilder::new(tempfile::tempfile().unwrap(), 5000, options);
+        builder.add_data("file0", noise(1000, 0)).add_data("file1", noise(8000, 1));
+        let err = builder.finish().unwrap_err();
+        assert_eq!(err.to_string(), "Must include file1, but it does not fit in the budget");
+    }
+
+    #[test]
+    fn test_groups() {
+        let group = |name: &str| Group {
+            name: name.into(),
+            share: Share::Percent(50),
+            patterns: vec![format!("{}*", name)],
+            paths: Vec::new(),
+        };
+        for round_robin in [false, true] {
+            let options =
+                Options { groups: vec![group("a"), group("b")], round_robin, ..Default::default() };
+            let output = tempfile::tempfile().unwrap();
+            let mut builder =
+                PartialArchiveBuilder::new(output.try_clone().unwrap(), 20000, options);
+            for i in 0..4 {
+                builder.add_data(format!("a{}", i), noise(4000, i));
+            }
+            builder.add_data("b0", noise(2000, 4));
+            let report = builder.finish().unwrap();
+
+            let tried: Vec<_> =
+                report.entries.iter().map(|e| e.name.display().to_string()).collect();
+            let archive = names(&read_archive(&output));
+            if round_robin {
+                assert_eq!(tried, ["a0", "b0", "a1", "a2", "a3"]);
+                assert_eq!(archive, ["a0", "b0", "a1", "a2", MANIFEST_NAME]);
+            } else {
+                assert_eq!(tried, ["a0", "a1", "a2", "a3", "b0"]);
+                /* a2 only gets in with what b left unused */
+                assert_eq!(archive, ["a0", "a1", "b0", "a2", MANIFEST_NAME]);
+            }
+            let a3 = report.entries.iter().find(|e| e.name == Path::new("a3")).unwrap();
+            assert_eq!(a3.status, EntryStatus::Skipped(SkipReason::DoesNotFit));
+
+            let a = &report.groups[0];
+            assert_eq!((a.name.as_str(), a.included, a.skipped), ("a", 3, 1));
+            assert!(a.used > a.quota);
+            let b = &report.groups[1];
+            assert_eq!((b.included, b.skipped), (1, 0));
+            assert!(b.used < b.quota);
+            assert!(report.size <= 20000);
+        }
+    }
+
+    #[test]
+    fn test_partial() {
+        let options = Options { partial: Some(Cut::Bytes), ..Default::default() };
+        let (report, output) = build(options, 10000, &[3000, 20000, 1000]);
+
+        let partial = &report.entries[1];
+        assert_eq!(partial.name, Path::new("file1.partial"));
+        assert!(matches!(partial.status, EntryStatus::Included { .. }));
+        let kept = partial.partial.clone().unwrap().kept;
+        assert_eq!(kept[0].start, 0);
+        assert_eq!(kept[1].end, 20000);
+        assert!(kept[0].end > 2000);
+        assert_eq!(report.entries[2].status, EntryStatus::Skipped(SkipReason::NotAttempted));
+
+        assert!(report.size <= 10000);
+        let archive = read_archive(&output);
+        assert_eq!(names(&archive), ["file0", "file1.partial", MANIFEST_NAME]);
+        let data = noise(20000, 1);
+        let mut expected = data[..kept[0].end as usize].to_vec();
+        expected.extend_from_slice(&data[kept[1].start as usize..]);
+        assert_eq!(archive[1].1, expected);
+        let manifest: serde_json::Value = serde_json::from_slice(&archive[2].1).unwrap();
+        assert_eq!(manifest["included"][1]["kept"][1][1], 20000);
+        assert_eq!(manifest["included"][1]["cut"], "bytes");
+    }
+
+    #[test]
+    fn test_partial_retries() {
+        /* Mostly zeros, so the ratio it compressed at fails for the noise
+         * at its end */
+        let mut data = vec![0; 30000];
+        data.extend(noise(10000, 1));
+        let input = Input { name: Some("dump".into()), source: Source::Data(data) };
+        let options = Options { partial: Some(Cut::Bytes), ..Default::default() };
+        let mut entry = input.report(&options).unwrap();
+        let output = Box::new(FileSink::new(tempfile::tempfile().unwrap()));
+        let compression = Compression::of(&options);
+        let mut archive =
+            tar::Builder::new(CheckpointWriter::new(output, compression, true).unwrap());
+
+        let size = add_partial(&options, &mut archive, &input, &mut entry, Cut::Bytes, 4000, 0.1);
+        assert!(size.unwrap().unwrap() <= 4000);
+        let kept = entry.partial.unwrap().kept;
+        assert_eq!(kept[1].end, 40000);
+        assert!(kept[1].start < 38000);
+    }
+
+    #[test]
+    fn test_partial_abandoned_gz() {
+        let log: String = (0..20000).map(|i| format!("line {}: all quiet\n", i)).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.log.gz");
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(log.as_bytes()).unwrap();
+        std::fs::write(&path, gz.finish().unwrap()).unwrap();
+
+        let options = Options {
+            format: Format::Uncompressed,
+            auto_decompress_gz: true,
+            partial: Some(Cut::Lines),
+            ..Default::default()
+        };
+        let output = tempfile::tempfile().unwrap();
+        let mut builder = PartialArchiveBuilder::new(output.try_clone().unwrap(), 20000, options);
+        builder.add_path(&path);
+        let report = builder.finish().unwrap();
+
+        /* Cut by the decompressed length though abandoned early */
+        let entry = &report.entries[0];
+        assert_eq!(entry.decompressed_size, Some(log.len() as u64));
+        assert!(entry.size < log.len() as u64 / 4);
+        let kept = entry.partial.clone().unwrap().kept;
+        assert_eq!(kept[1].end, log.len() as u64);
+
+        let archive = read_archive(&output);
+        let stored = std::str::from_utf8(&archive[0].1).unwrap();
+        assert!(stored.starts_with("line 0: all quiet\n"));
+        assert!(stored.ends_with("line 19999: all quiet\n"));
+    }
+
+    #[test]
+    fn test_partial_json() {
+        let samples: Vec<_> = (0..200)
+            .map(|i| {
+                let hex: String = noise(100, i).iter().map(|b| format!("{:02x}", b)).collect();
+                format!("{{\"t\": {}, \"data\": \"{}\"}}", i, hex)
+            })
+            .collect();
+        let dump = format!("{{\"host\": \"db1\", \"samples\": [{}]}}", samples.join(", "));
+        let lines = samples.join("\n");
+
+        /* Each fills the budget, so they are stored on their own */
+        let build = |name: &str, data: &str| {
+            let options = Options { partial: Some(Cut::Json), ..Default::default() };
+            let output = tempfile::tempfile().unwrap();
+            let mut builder =
+                PartialArchiveBuilder::new(output.try_clone().unwrap(), 10000, options);
+            builder.add_data(name, data.to_string());
+            let report = builder.finish().unwrap();
+            assert!(report.size <= 10000);
+            (report.entries[0].partial.clone().unwrap().cut, read_archive(&output))
+        };
+
+        let (cut, archive) = build("dump.json", &dump);
+        assert_eq!(cut, Cut::Json);
+        assert_eq!(names(&archive), ["dump.json.partial", MANIFEST_NAME]);
+        let stored: serde_json::Value = serde_json::from_slice(&archive[0].1).unwrap();
+        let kept = stored["samples"].as_array().unwrap();
+        assert!(!kept.is_empty() && kept.len() < 200);
+        assert_eq!(stored["samples"][0]["t"], 0);
+        let manifest: serde_json::Value = serde_json::from_slice(&archive[1].1).unwrap();
+        assert_eq!(manifest["included"][0]["cut"], "json");
+
+        let (cut, archive) = build("dump.log", &lines);
+        assert_eq!(cut, Cut::Lines);
+        assert_eq!(names(&archive), ["dump.log.partial", MANIFEST_NAME]);
+        let stored = std::str::from_utf8(&archive[0].1).unwrap();
+        let stored: Vec<_> = stored.lines().collect();
+        assert!(stored.iter().all(|line| samples.contains(&line.to_string())));
+        assert_eq!(stored[0], samples[0]);
+    }
+
+    #[test]
+    fn test_volumes() {
+        let options = Options { skip_oversized: true, ..Default::default() };
+        let mut files = Vec::new();
+        let create = |_| {
+            let file = tempfile::tempfile()?;
+            files.push(file.try_clone()?);
+            Ok(file)
+        };
+        let mut builder = PartialArchiveBuilder::new_volumes(create, 3, 10000, options);
+        for (i, size) in [3000, 3000, 12000, 3000, 3000, 3000, 3000, 3000].into_iter().enumerate() {
+            builder.add_data(format!("file{}", i), noise(size, i as u64));
+        }
+        let report = builder.finish().unwrap();
+
+        assert_eq!(report.volumes.len(), 3);
+        assert_eq!(report.size, report.volumes.iter().sum::<u64>());
+        let volumes: Vec<_> = report.entries.iter().map(|e| e.volume).collect();
+        assert_eq!(volumes, [Some(1), Some(1), Some(2), Some(2), Some(3), Some(3), None, None]);
+        let skipped: Vec<_> = report.skipped().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(skipped, ["file2", "file7"]);
+
+        assert_eq!(files.len(), 3);
+        for (file, size) in files.iter().zip(&report.volumes) {
+            assert!(*size <= 10000);
+            let archive = read_archive(file);
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&archive.last().unwrap().1).unwrap();
+            assert_eq!(archive.len(), 3);
+            assert_eq!(manifest["included"].as_array().unwrap().len(), 2);
+        }
+
+        let index: serde_json::Value = serde_json::from_str(&report.index()).unwrap();
+        assert_eq!(index["volumes"][1]["included"][1]["name"], "file4");
+        assert_eq!(index["volumes"][1]["included"][1]["volume"], 2);
+        assert_eq!(index["skipped"][0]["reason"], "does-not-fit");
+
+        /* Without skipping, an input that fits no volume is dropped, and
+         * no volume is created for nothing */
+        let mut created = 0;
+        let create = |_| {
+            created += 1;
+            Ok(tempfile::tempfile()?)
+        };
+        let mut builder =
+            PartialArchiveBuilder::new_volumes(create, usize::MAX, 10000, Options::default());
+        for (i, size) in [3000, 3000, 12000, 3000, 3000].into_iter().enumerate() {
+            builder.add_data(format!("file{}", i), noise(size, i as u64));
+        }
+        let report = builder.finish().unwrap();
+
+        assert_eq!(report.volumes.len(), 2);
+        let volumes: Vec<_> = report.entries.iter().map(|e| e.volume).collect();
+        assert_eq!(volumes, [Some(1), Some(1), None, Some(2), Some(2)]);
+        assert_eq!(report.entries[2].status, EntryStatus::Skipped(SkipReason::DoesNotFit));
+        assert_eq!(created, 2);
+
+        /* The dropped input keeps why it was left out */
+        let options = Options { prescreen: true, ..Default::default() };
+        let create = |_| Ok(tempfile::tempfile()?);
+        let mut builder = PartialArchiveBuilder::new_volumes(create, 3, 10000, options);
+        for (i, size) in [3000, 60000, 3000].into_iter().enumerate() {
+            builder.add_data(format!("file{}", i), noise(size, i as u64));
+        }
+        let report = builder.finish().unwrap();
+
+        let volumes: Vec<_> = report.entries.iter().map(|e| e.volume).collect();
+        assert_eq!(volumes, [Some(1), None, Some(2)]);
+        assert_eq!(report.entries[1].status, EntryStatus::Skipped(SkipReason::ScreenedOut));
+    }
+
+    #[test]
+    fn test_tiers() {
+        let output = tempfile::tempfile().unwrap();
+        let (small, large) = (tempfile::tempfile().unwrap(), tempfile::tempfile().unwrap());
+        let mut builder =
+            PartialArchiveBuilder::new(output.try_clone().unwrap(), 10000, Options::default());
+        builder.add_tier(small.try_clone().unwrap(), 7000);
+        builder.add_tier(large.try_clone().unwrap(), 20000);
+        for i in 0..8 {
+            builder.add_data(format!("file{}", i), noise(3000, i));
+        }
+        let report = builder.finish().unwrap();
+
+        let counts: Vec<_> =
+            std::iter::once(&report).chain(&report.tiers).map(|r| r.included().count()).collect();
+        assert_eq!(counts, [2, 1, 5]);
+
+        for (file, (report, max_size)) in [&output, &small, &large]
+            .into_iter()
+            .zip([&report].into_iter().chain(&report.tiers).zip([10000, 7000, 20000]))
+        {
+            assert!(report.size <= max_size);
+            let archive = read_archive(file);
+            assert_eq!(archive.len(), report.included().count() + 1);
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&archive.last().unwrap().1).unwrap();
+            assert_eq!(manifest["budget"], max_size);
+            assert_eq!(manifest["skipped"][0]["reason"], "does-not-fit");
+        }
+
+        let options = Options { skip_oversized: true, ..Default::default() };
+        let mut builder = PartialArchiveBuilder::new(tempfile::tempfile().unwrap(), 10000, options);
+        builder.add_tier(tempfile::tempfile().unwrap(), 5000);
+        assert!(builder.finish().is_err());
+    }
+
+    #[test]
+    fn test_write_order() {
+        let options = Options {
+            order: Order::TimestampNewest,
+            write_order: Some(Order::TimestampOldest),
+            ..Default::default()
+        };
+        let output = tempfile::tempfile().unwrap();
+        let mut builder = PartialArchiveBuilder::new(output.try_clone().unwrap(), 10000, options);
+        for i in [3, 1, 4, 0] {
+            builder.add_data(format!("dump-172408716{}.bin", i), noise(2500, i));
+        }
+        let data = noise(2500, 2);
+        builder.add_reader("dump-1724087162.bin", 2500, std::io::Cursor::new(data.clone()));
+        let report = builder.finish().unwrap();
+
+        let included: Vec<_> = report.included().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(included, ["dump-1724087162.bin", "dump-1724087163.bin", "dump-1724087164.bin"]);
+        let skipped: Vec<_> = report.skipped().map(|e| e.name.display().to_string()).collect();
+        assert_eq!(skipped, ["dump-1724087161.bin", "dump-1724087160.bin"]);
+
+        assert!(report.size <= 10000);
+        let archive = read_archive(&output);
+        assert_eq!(names(&archive)[..3], included);
+        assert_eq!(archive[0].1, data);
+    }
+}
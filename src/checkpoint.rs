@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::compressor::{new_compressor, BudgetCompressor, Compression, Output};
+use crate::sink::Sink;
+
+/* Input is handed to the compressor in blocks of this size (and a
+ * final partial block at each checkpoint). That makes the compressor
+ * calls depend only on the data and the checkpoint positions, which is
+ * what allows replaying the stream identically in `rewind`. */
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Error writing to a [`CheckpointWriter`] once its output has grown
+/// beyond the limit, see [`CheckpointWriter::set_limit`].
+#[derive(Debug)]
+pub(crate) struct OverLimit;
+
+impl std::fmt::Display for OverLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Output is over the limit")
+    }
+}
+
+impl std::error::Error for OverLimit {}
+
+/// Whether `error` is caused by [`OverLimit`].
+pub(crate) fn is_over_limit(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|e| e.is::<OverLimit>())
+    })
+}
+
+#[derive(Clone, Copy, Default)]
+struct Checkpoint {
+    /// Compressed bytes written.
+    pos: u64,
+    /// Uncompressed bytes written.
+    spool_len: u64,
+    /// Number of compressor flushes so far.
+    flushes: usize,
+}
+
+/// Compresses with a [`BudgetCompressor`], so that the output can be cut
+/// back to the last checkpoint.
+///
+/// Every checkpoint flushes the compressor so the compressed stream up
+/// to it ends at a byte boundary. The output can then always be
+/// truncated there and terminated (see [`truncate_and_close`](Self::truncate_and_close)).
+///
+/// Continuing to compress after cutting back requires the compressor
+/// state as it was at the checkpoint. Compressors can't be cloned, so
+/// when `rewindable` the uncompressed input is kept in a spool file and
+/// [`rewind`](Self::rewind) recompresses it with a fresh compressor.
+pub(crate) struct CheckpointWriter<'s> {
+    compression: Compression,
+    compressor: Box<dyn BudgetCompressor<'s> + 's>,
+    block: Vec<u8>,
+    spool: Option<File>,
+    /// Spool offsets of all compressor flushes.
+    flushes: Vec<u64>,
+    spool_len: u64,
+    checkpoint: Checkpoint,
+    limit: Option<u64>,
+}
+
+impl<'s> CheckpointWriter<'s> {
+    pub(crate) fn new(
+        output: Box<dyn Sink + 's>,
+        compression: Compression,
+        rewindable: bool,
+    ) -> Result<Self> {
+        let spool = if rewindable {
+            Some(tempfile::tempfile().context("Could not create spool file")?)
+        } else {
+            None
+        };
+
+        Ok(CheckpointWriter {
+            compression,
+            compressor: new_compressor(compression, Output { sink: Some(output), skip: 0 })?,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            spool,
+            flushes: Vec::new(),
+            spool_len: 0,
+            checkpoint: Checkpoint::default(),
+            limit: None,
+        })
+    }
+
+    fn write_block(&mut self) -> std::io::Result<()> {
+        self.compressor.write_all(&self.block)?;
+        if let Some(spool) = &mut self.spool {
+            spool.write_all(&self.block)?;
+        }
+        self.spool_len += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn flush_compressor(&mut self) -> std::io::Result<()> {
+        self.write_block()?;
+        self.compressor.flush()?;
+        self.flushes.push(self.spool_len);
+        Ok(())
+    }
+
+    fn sink(&mut self) -> &mut (dyn Sink + 's) {
+        self.compressor.output().sink.as_deref_mut().expect("compressor has the sink")
+    }
+
+    fn take_sink(&mut self) -> Box<dyn Sink + 's> {
+        self.compressor.output().sink.take().expect("compressor has the sink")
+    }
+
+    /// Makes writing fail with [`OverLimit`] as soon as the compressed
+    /// output is larger than `limit`, even before it is flushed. The
+    /// writer must then be rewound or truncated.
+    pub(crate) fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+    }
+
+    /// Size of the compressed output so far, without flushing. What the
+    /// compressor still holds comes on top.
+    pub(crate) fn position(&mut self) -> u64 {
+        self.compressor.size()
+    }
+
+    /// Flushes everything written so far and returns the size of the
+    /// compressed output at this point.
+    pub(crate) fn flush_and_get_position(&mut self) -> Result<u64> {
+        self.flush_compressor().context("Could not flush output")?;
+        Ok(self.compressor.size())
+    }
+
+    /// Like [`flush_and_get_position`](Self::flush_and_get_position),
+    /// and makes this the point that [`rewind`](Self::rewind) and
+    /// [`truncate_and_close`](Self::truncate_and_close) return to.
+    /// Output before it can't be taken back anymore.
+    pub(crate) fn checkpoint(&mut self) -> Result<u64> {
+        let pos = self.flush_and_get_position()?;
+        self.sink().commit().context("Could not write output")?;
+        self.compressor.checkpoint();
+        self.checkpoint =
+            Checkpoint { pos, spool_len: self.spool_len, flushes: self.flushes.len() };
+
+        Ok(pos)
+    }
+
+    /// Uncompressed bytes written since the last checkpoint.
+    pub(crate) fn since_checkpoint(&self) -> u64 {
+        self.spool_len + self.block.len() as u64 - self.checkpoint.spool_len
+    }
+
+    /// Uncompressed bytes up to the last checkpoint, which is what
+    /// [`rewind`](Self::rewind) recompresses. When `rewindable` the spool
+    /// file is as large.
+    pub(crate) fn spooled(&self) -> u64 {
+        self.checkpoint.spool_len
+    }
+
+    /// Throws away everything written after the last checkpoint, leaving
+    /// the writer as it was directly after that checkpoint.
+    pub(crate) fn rewind(&mut self) -> Result<()> {
+        let Checkpoint { pos, spool_len, flushes } = self.checkpoint;
+        let mut spool = self.spool.take().expect("rewind needs a spool");
+
+        let mut sink = self.take_sink();
+        sink.rollback().context("Could not truncate output")?;
+        self.compressor = new_compressor(self.compression, Output { sink: Some(sink), skip: pos })?;
+        self.block.clear();
+
+        spool.set_len(spool_len).context("Could not truncate spool")?;
+        spool.seek(SeekFrom::Start(0)).context("Could not seek spool")?;
+
+        self.flushes.truncate(flushes);
+        let replay = std::mem::take(&mut self.flushes);
+        self.spool_len = 0;
+
+        /* Feed the spool to the new compressor exactly like Write::write
+         * and checkpoint() did originally. The spool is kept aside
+         * meanwhile, as it already holds this data. */
+        let mut replayed = 0;
+        for flush_at in replay {
+            while replayed < flush_at {
+                let start = self.block.len();
+                let n = std::cmp::min((BLOCK_SIZE - start) as u64, flush_at - replayed);
+                self.block.resize(start + n as usize, 0);
+                spool.read_exact(&mut self.block[start..]).context("Could not read spool")?;
+                replayed += n;
+                if self.block.len() == BLOCK_SIZE {
+                    self.write_block().context("Could not recompress spool")?;
+                }
+            }
+            self.flush_compressor().context("Could not recompress spool")?;
+        }
+        self.spool = Some(spool);
+
+        if self.compressor.output().skip != 0 || self.compressor.size() != pos {
+            bail!("Recompressing the archive did not reproduce the same output");
+        }
+        self.compressor.checkpoint();
+
+        Ok(())
+    }
+
+    /// Completes the compressed stream normally and returns its size.
+    ///
+    /// Should that exceed `max_size`, the output is instead cut back
+    /// like [`truncate_and_close`](Self::truncate_and_close) does.
+    pub(crate) fn finish(mut self, max_size: u64, tail: &[u8]) -> Result<u64> {
+        self.write_block().context("Could not write output")?;
+        let discarding = new_compressor(self.compression, Output { sink: None, skip: 0 })?;
+        let compressor = std::mem::replace(&mut self.compressor, discarding);
+        let output = compressor.finish().context("Could not finish compressing")?;
+        let mut sink = output.sink.expect("compressor has the sink");
+
+        let size = sink.position();
+        if size <= max_size {
+            sink.commit().context("Could not write output")?;
+            return Ok(size);
+        }
+
+        self.compressor.output().sink = Some(sink);
+        self.truncate_and_close(tail)
+    }
+
+    /// Cuts the output back to the last checkpoint, appends `tail`
+    /// uncompressed followed by tar's end of archive marker and
+    /// terminates the stream there, returns its size.
+    ///
+    /// The resulting size is at most the checkpoint position plus
+    /// [`tail_size`](Self::tail_size) of the tail.
+    pub(crate) fn truncate_and_close(mut self, tail: &[u8]) -> Result<u64> {
+        let mut sink = self.take_sink();
+        let end = self.end_at_checkpoint(tail);
+
+        sink.rollback().context("Could not truncate output")?;
+        sink.write_all(&end).context("Could not write end of archive")?;
+        sink.commit().context("Could not write end of archive")?;
+
+        Ok(self.checkpoint.pos + end.len() as u64)
+    }
+
+    /// What [`truncate_and_close`](Self::truncate_and_close) appends at
+    /// the last checkpoint, for completing copies of the output there.
+    pub(crate) fn end_at_checkpoint(&self, tail: &[u8]) -> Vec<u8> {
+        /* A flush() call has been made on the compressor at the
+         * checkpoint, so that this position is always at a byte
+         * boundary where the stream can be completed. */
+        self.compressor.finalize_truncated(self.checkpoint.pos, tail)
+    }
+
+    /// Most bytes [`truncate_and_close`](Self::truncate_and_close) adds
+    /// after the checkpoint for a tail of `len` bytes.
+    pub(crate) fn tail_size(&self, len: u64) -> u64 {
+        self.compressor.finalize_truncated_len(len)
+    }
+
+    /// The compression format and its parameters.
+    pub(crate) fn parameters(&self) -> Value {
+        self.compressor.parameters()
+    }
+}
+
+impl Write for CheckpointWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = std::cmp::min(buf.len(), BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == BLOCK_SIZE {
+            self.write_block()?;
+            if self.limit.is_some_and(|limit| self.compressor.size() > limit) {
+                return Err(std::io::Error::other(OverLimit));
+            }
+        }
+        Ok(n)
+    }
+
+    /* Flushing the compressor changes the compressed stream, so that is
+     * only done at checkpoints. */
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
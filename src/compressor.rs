@@ -0,0 +1,365 @@
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::builder::{BrotliParams, Format, Options};
+use crate::sink::Sink;
+use crate::{deflate_block, metablock, zstd_block};
+
+const ZSTD_LEVEL: i32 = 19;
+const GZIP_LEVEL: u32 = 9;
+
+/// Size of tar's end of archive marker, two blocks filled with 0x00.
+const END_OF_ARCHIVE_LEN: usize = 1024;
+
+/// Where the compressed output goes. While replaying the first `skip`
+/// bytes are already in the sink and are only counted. Without a sink
+/// the output is discarded, for compressors that are thrown away.
+pub(crate) struct Output<'s> {
+    pub(crate) sink: Option<Box<dyn Sink + 's>>,
+    pub(crate) skip: u64,
+}
+
+impl Write for Output<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(sink) = &mut self.sink else {
+            return Ok(buf.len());
+        };
+        if self.skip > 0 {
+            let n = std::cmp::min(self.skip, buf.len() as u64) as usize;
+            self.skip -= n as u64;
+            return Ok(n);
+        }
+        sink.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A compression format that [`CheckpointWriter`] can keep within a
+/// budget.
+///
+/// Uncompressed data is written to it, and [`Write::flush`] must end
+/// the output so far at a byte boundary, with all data written so far
+/// decodable from it. The output may then be cut back to there and
+/// completed by [`finalize_truncated`](Self::finalize_truncated),
+/// without any help from the compressor state after that point.
+///
+/// [`CheckpointWriter`]: crate::checkpoint::CheckpointWriter
+pub(crate) trait BudgetCompressor<'s>: Write {
+    /// Marks the point directly after a flush that the output may be
+    /// cut back to later.
+    fn checkpoint(&mut self) {}
+
+    fn output(&mut self) -> &mut Output<'s>;
+
+    /// Size of the compressed output so far, all of it after a flush.
+    fn size(&mut self) -> u64 {
+        self.output().sink.as_ref().map_or(0, |sink| sink.position())
+    }
+
+    /// Completes the compressed stream normally.
+    fn finish(self: Box<Self>) -> std::io::Result<Output<'s>>;
+
+    /// What completes the output cut back to the last checkpoint, which
+    /// was at `pos`: `tail` uncompressed followed by tar's end of
+    /// archive marker.
+    fn finalize_truncated(&self, pos: u64, tail: &[u8]) -> Vec<u8>;
+
+    /// Most bytes [`finalize_truncated`](Self::finalize_truncated)
+    /// returns for a tail of `len` bytes.
+    fn finalize_truncated_len(&self, len: u64) -> u64;
+
+    /// The format and its parameters, as recorded in the manifest.
+    fn parameters(&self) -> Value;
+}
+
+/// The compression format with its settings.
+#[derive(Clone, Copy)]
+pub(crate) struct Compression {
+    pub(crate) format: Format,
+    pub(crate) brotli: BrotliParams,
+}
+
+impl Compression {
+    pub(crate) fn of(options: &Options) -> Self {
+        Compression { format: options.format, brotli: options.brotli }
+    }
+}
+
+pub(crate) fn new_compressor<'s>(
+    compression: Compression,
+    output: Output<'s>,
+) -> Result<Box<dyn BudgetCompressor<'s> + 's>> {
+    Ok(match compression.format {
+        Format::Brotli => Box::new(Brotli::new(output, compression.brotli)?),
+        Format::Zstd => Box::new(Zstd::new(output)?),
+        Format::Gzip => Box::new(Gzip::new(output)?),
+        Format::Uncompressed => Box::new(Uncompressed(output)),
+    })
+}
+
+struct Brotli<'s>(brotli::CompressorWriter<Output<'s>>, BrotliParams);
+
+impl<'s> Brotli<'s> {
+    fn new(output: Output<'s>, params: BrotliParams) -> Result<Self> {
+        params.check()?;
+        let BrotliParams { quality, lgwin, large_window, buffer_size } = params;
+        let encoder = brotli::enc::BrotliEncoderParams {
+            quality: quality as i32,
+            lgwin: lgwin as i32,
+            large_window,
+            ..Default::default()
+        };
+        Ok(Brotli(brotli::CompressorWriter::with_params(output, buffer_size, &encoder), params))
+    }
+}
+
+impl Write for Brotli<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    /* Ends the current metadata block, at a byte boundary */
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'s> BudgetCompressor<'s> for Brotli<'s> {
+    fn output(&mut self) -> &mut Output<'s> {
+        self.0.get_mut()
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<Output<'s>> {
+        Ok(self.0.into_inner())
+    }
+
+    /* The tail is appended as uncompressed metadata blocks, those only
+     * need to start at a byte boundary and don't depend on any encoder
+     * state.
+     *
+     * The tar file also should contain a end of file marker (two blocks
+     * filled with 0x00). GNU tar ignores when that is missing (unless
+     * run with `--warning=missing-zero-blocks` option) but stricter
+     * readers don't. It is cheaply written as a tiny compressed
+     * metadata block, copying a single zero byte.
+     *
+     * All brotli files must end with a metadata block with the "ISLAST"
+     * flag set. CompressorWriter::drop writes that automatically, but
+     * that is lost when the file is truncated. So it must be written
+     * manually here. Luckily such empty last metadata block is really
+     * easy to write, as it is just two bits set (ISLAST and
+     * ISLASTEMPTY). [RFC7932 9.2]
+     */
+    fn finalize_truncated(&self, _pos: u64, tail: &[u8]) -> Vec<u8> {
+        let mut end = Vec::with_capacity(self.finalize_truncated_len(tail.len() as u64) as usize);
+        metablock::stored(tail, &mut end);
+        end.extend_from_slice(&metablock::zeros_and_last(END_OF_ARCHIVE_LEN));
+        end
+    }
+
+    fn finalize_truncated_len(&self, len: u64) -> u64 {
+        metablock::stored_size(len) + metablock::zeros_and_last(END_OF_ARCHIVE_LEN).len() as u64
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "format": "brotli",
+            "quality": self.1.quality,
+            "lgwin": self.1.lgwin,
+            "large_window": self.1.large_window,
+            "buffer_size": self.1.buffer_size,
+        })
+    }
+}
+
+struct Zstd<'s>(zstd::stream::write::Encoder<'static, Output<'s>>);
+
+impl<'s> Zstd<'s> {
+    fn new(output: Output<'s>) -> Result<Self> {
+        let mut encoder = zstd::stream::write::Encoder::new(output, ZSTD_LEVEL)
+            .context("Could not create zstd encoder")?;
+        /* Nothing may follow the last block, see zstd_block */
+        encoder.include_checksum(false).context("Could not configure zstd encoder")?;
+        Ok(Zstd(encoder))
+    }
+}
+
+impl Write for Zstd<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    /* Ends the current block, but not the frame */
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'s> BudgetCompressor<'s> for Zstd<'s> {
+    fn output(&mut self) -> &mut Output<'s> {
+        self.0.get_mut()
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<Output<'s>> {
+        self.0.finish()
+    }
+
+    /* Raw blocks with the tail follow, and the frame is ended by the
+     * end of archive marker as a last RLE block.
+     *
+     * Before it has been given any data the encoder writes nothing at
+     * all, not even the frame header. */
+    fn finalize_truncated(&self, pos: u64, tail: &[u8]) -> Vec<u8> {
+        let mut end = Vec::with_capacity(self.finalize_truncated_len(tail.len() as u64) as usize);
+        if pos == 0 {
+            end.extend_from_slice(&zstd_block::FRAME_HEADER);
+        }
+        zstd_block::raw(tail, &mut end);
+        end.extend_from_slice(&zstd_block::zeros_and_last(END_OF_ARCHIVE_LEN));
+        end
+    }
+
+    fn finalize_truncated_len(&self, len: u64) -> u64 {
+        zstd_block::FRAME_HEADER.len() as u64
+            + zstd_block::raw_size(len)
+            + zstd_block::zeros_and_last(END_OF_ARCHIVE_LEN).len() as u64
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "format": "zstd",
+            "level": ZSTD_LEVEL,
+        })
+    }
+}
+
+/// Raw deflate with the gzip header and trailer written around it, so
+/// the CRC is at hand when completing a cut back stream.
+struct Gzip<'s> {
+    deflate: flate2::write::DeflateEncoder<Output<'s>>,
+    crc: crc32fast::Hasher,
+    len: u64,
+    /// CRC and length of the data up to the last checkpoint.
+    checkpoint: (u32, u64),
+}
+
+impl<'s> Gzip<'s> {
+    fn new(mut output: Output<'s>) -> Result<Self> {
+        output.write_all(&deflate_block::GZIP_HEADER).context("Could not write output")?;
+        let level = flate2::Compression::new(GZIP_LEVEL);
+
+        Ok(Gzip {
+            deflate: flate2::write::DeflateEncoder::new(output, level),
+            crc: crc32fast::Hasher::new(),
+            len: 0,
+            checkpoint: (0, 0),
+        })
+    }
+}
+
+impl Write for Gzip<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.deflate.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    /* A sync flush, ending with an empty stored block. A full flush
+     * would also reset the dictionary, which isn't needed as everything
+     * appended after truncating is self-contained anyway. */
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.deflate.flush()
+    }
+}
+
+impl<'s> BudgetCompressor<'s> for Gzip<'s> {
+    fn checkpoint(&mut self) {
+        self.checkpoint = (self.crc.clone().finalize(), self.len);
+    }
+
+    fn output(&mut self) -> &mut Output<'s> {
+        self.deflate.get_mut()
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<Output<'s>> {
+        let trailer = deflate_block::trailer(self.crc.finalize(), self.len);
+        let mut output = self.deflate.finish()?;
+        output.write_all(&trailer)?;
+        Ok(output)
+    }
+
+    /* Stored blocks with the tail follow, then the end of archive
+     * marker as the final block. The trailer covers everything up to
+     * the checkpoint too, so the CRC as of it is kept. */
+    fn finalize_truncated(&self, _pos: u64, tail: &[u8]) -> Vec<u8> {
+        let mut end = Vec::with_capacity(self.finalize_truncated_len(tail.len() as u64) as usize);
+        deflate_block::stored(tail, &mut end);
+        end.extend_from_slice(&deflate_block::zeros_and_last(END_OF_ARCHIVE_LEN));
+
+        let (crc, len) = self.checkpoint;
+        let mut crc = crc32fast::Hasher::new_with_initial_len(crc, len);
+        crc.update(tail);
+        crc.update(&[0; END_OF_ARCHIVE_LEN]);
+        let len = len + (tail.len() + END_OF_ARCHIVE_LEN) as u64;
+        end.extend_from_slice(&deflate_block::trailer(crc.finalize(), len));
+        end
+    }
+
+    fn finalize_truncated_len(&self, len: u64) -> u64 {
+        deflate_block::stored_size(len)
+            + deflate_block::zeros_and_last(END_OF_ARCHIVE_LEN).len() as u64
+            + deflate_block::trailer(0, 0).len() as u64
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "format": "gzip",
+            "level": GZIP_LEVEL,
+        })
+    }
+}
+
+/// Plain tar, which can be cut anywhere.
+struct Uncompressed<'s>(Output<'s>);
+
+impl Write for Uncompressed<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'s> BudgetCompressor<'s> for Uncompressed<'s> {
+    fn output(&mut self) -> &mut Output<'s> {
+        &mut self.0
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<Output<'s>> {
+        Ok(self.0)
+    }
+
+    fn finalize_truncated(&self, _pos: u64, tail: &[u8]) -> Vec<u8> {
+        let mut end = tail.to_vec();
+        end.resize(tail.len() + END_OF_ARCHIVE_LEN, 0);
+        end
+    }
+
+    fn finalize_truncated_len(&self, len: u64) -> u64 {
+        len + END_OF_ARCHIVE_LEN as u64
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "format": "none",
+        })
+    }
+}
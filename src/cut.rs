@@ -0,0 +1,118 @@
+//! Finding where to cut the contents of an input that is stored
+//! partially, so that what is kept is still whole lines or valid JSON.
+
+use std::io::{BufRead, BufReader, Read};
+
+/// Length of the longest prefix of the first `len` bytes of `reader`
+/// that ends with a line end, 0 when there is none.
+pub(crate) fn last_line_end<R: Read>(reader: R, len: u64) -> std::io::Result<u64> {
+    let mut reader = BufReader::new(reader.take(len));
+    let (mut pos, mut end) = (0, 0);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(end);
+        }
+        if let Some(i) = buf.iter().rposition(|b| *b == b'\n') {
+            end = pos + i as u64 + 1;
+        }
+        let n = buf.len();
+        reader.consume(n);
+        pos += n as u64;
+    }
+}
+
+/// Offset in `reader` directly after its first line end, if any.
+pub(crate) fn first_line_start<R: Read>(reader: R) -> std::io::Result<Option<u64>> {
+    let mut line = Vec::new();
+    let n = BufReader::new(reader).read_until(b'\n', &mut line)?;
+    Ok(line.ends_with(b"\n").then_some(n as u64))
+}
+
+/// Where to cut the JSON document read from `reader` within its first
+/// `len` bytes and what to append there to keep it valid JSON.
+///
+/// Trailing array elements and object members are dropped and the
+/// containers still open are closed again. Nothing is found when the
+/// cut would fall within the first value that is no container, like a
+/// long string at the top level.
+pub(crate) fn json_prefix<R: Read>(reader: R, len: u64) -> std::io::Result<Option<(u64, Vec<u8>)>> {
+    /* Closers of the open containers */
+    let mut open = Vec::new();
+    let (mut in_string, mut escaped) = (false, false);
+    /* Last position after a complete element, with the number of
+     * containers open there */
+    let mut cut = None;
+
+    for (pos, b) in (0..).zip(BufReader::new(reader.take(len)).bytes()) {
+        let b = b?;
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                open.push(if b == b'[' { b']' } else { b'}' });
+                /* Nested ones are only kept with some of their contents */
+                if open.len() == 1 {
+                    cut = Some((pos + 1, open.len()));
+                }
+            }
+            b']' | b'}' => {
+                open.pop();
+                cut = Some((pos + 1, open.len()));
+            }
+            b',' => cut = Some((pos, open.len())),
+            _ => {}
+        }
+    }
+
+    Ok(cut.map(|(pos, depth)| (pos, open[..depth].iter().rev().copied().collect())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let text = b"first\nsecond\nthird";
+        assert_eq!(last_line_end(&text[..], 12).unwrap(), 6);
+        assert_eq!(last_line_end(&text[..], 13).unwrap(), 13);
+        assert_eq!(last_line_end(&text[..], 5).unwrap(), 0);
+        assert_eq!(first_line_start(&text[3..]).unwrap(), Some(3));
+        assert_eq!(first_line_start(&text[14..]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_json_prefix() {
+        let doc =
+            br#"{"host": "db1", "samples": [{"t": 1, "msg": "a,]}\""}, {"t": 2}], "end": true}"#;
+        let shortened = |len| {
+            let (pos, closers) = json_prefix(&doc[..], len).unwrap()?;
+            let mut json = doc[..pos as usize].to_vec();
+            json.extend_from_slice(&closers);
+            Some(String::from_utf8(json).unwrap())
+        };
+
+        for len in 1..=doc.len() as u64 {
+            let json = shortened(len).unwrap();
+            assert!(json.len() as u64 <= len + 3);
+            serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        }
+        assert_eq!(shortened(1).unwrap(), "{}");
+        assert_eq!(
+            shortened(60).unwrap(),
+            r#"{"host": "db1", "samples": [{"t": 1, "msg": "a,]}\""}]}"#
+        );
+        assert_eq!(shortened(doc.len() as u64).unwrap(), std::str::from_utf8(doc).unwrap());
+
+        assert_eq!(json_prefix(&br#""a long string""#[..], 5).unwrap(), None);
+    }
+}
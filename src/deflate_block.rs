@@ -0,0 +1,172 @@
+//! Deflate blocks and gzip framing written by hand, used to complete a
+//! gzip stream that has been cut back to a checkpoint [RFC1951, RFC1952].
+//!
+//! Neither kind of block depends on the encoder state at the checkpoint:
+//! stored blocks hold their data as is, and the block of zeros only
+//! refers back to its own first byte.
+
+use crate::bits::BitWriter;
+
+/// Member header [RFC1952 2.3]: ID1, ID2, CM (deflate), FLG (none),
+/// MTIME (none), XFL (maximum compression), OS (unknown).
+pub(crate) const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 2, 255];
+
+/// Largest stored block [RFC1951 3.2.4].
+const STORED_BLOCK_SIZE: usize = 65535;
+
+/* Length codes 257..=285 as (first length, extra bits) [RFC1951 3.2.5] */
+const LENGTHS: [(u32, usize); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// Appends `data` as stored (non-final) blocks. Each has a header of
+/// BFINAL (0) and BTYPE (00) padded to a byte boundary, LEN and NLEN.
+pub(crate) fn stored(data: &[u8], out: &mut Vec<u8>) {
+    for chunk in data.chunks(STORED_BLOCK_SIZE) {
+        let len = chunk.len() as u16;
+        out.push(0);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Number of bytes [`stored`] appends for `len` bytes of data.
+pub(crate) fn stored_size(len: u64) -> u64 {
+    len + 5 * len.div_ceil(STORED_BLOCK_SIZE as u64)
+}
+
+/// Huffman codes are packed starting from their most significant bit
+/// [RFC1951 3.1.1].
+fn write_code(w: &mut BitWriter, nbits: usize, code: u32) {
+    w.write(nbits, code.reverse_bits() >> (32 - nbits));
+}
+
+/// Symbol of the fixed literal/length code [RFC1951 3.2.6].
+fn write_fixed_symbol(w: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => write_code(w, 8, 0x30 + symbol),
+        256..=279 => write_code(w, 7, symbol - 256),
+        280..=287 => write_code(w, 8, 0xc0 + symbol - 280),
+        _ => unreachable!("not used here"),
+    }
+}
+
+/// The final block, `len` (at least 4) zero bytes compressed with the
+/// fixed codes, padded to a byte boundary.
+///
+/// It holds a literal zero followed by copies from distance 1.
+pub(crate) fn zeros_and_last(len: usize) -> Vec<u8> {
+    assert!(len >= 4);
+
+    let mut w = BitWriter::new();
+    w.write(1, 1); // BFINAL
+    w.write(2, 1); // BTYPE: fixed Huffman codes
+
+    write_fixed_symbol(&mut w, 0);
+
+    let mut remaining = len as u32 - 1;
+    while remaining > 0 {
+        /* Copies are at least 3 long, so don't leave less than that */
+        let mut copy_len = std::cmp::min(remaining, 258);
+        if (1..3).contains(&(remaining - copy_len)) {
+            copy_len = remaining - 3;
+        }
+        remaining -= copy_len;
+
+        let code = LENGTHS.iter().rposition(|(first, _)| *first <= copy_len).unwrap();
+        let (first, extra_bits) = LENGTHS[code];
+        write_fixed_symbol(&mut w, 257 + code as u32);
+        w.write(extra_bits, copy_len - first);
+        write_code(&mut w, 5, 0); // distance code 0: distance 1
+    }
+
+    write_fixed_symbol(&mut w, 256); // end of block
+
+    w.bytes
+}
+
+/// Member trailer [RFC1952 2.3]: CRC32 and ISIZE of the uncompressed data.
+pub(crate) fn trailer(crc: u32, len: u64) -> [u8; 8] {
+    let mut trailer = [0; 8];
+    trailer[..4].copy_from_slice(&crc.to_le_bytes());
+    trailer[4..].copy_from_slice(&(len as u32).to_le_bytes());
+    trailer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_complete_truncated_stream() {
+        let mut stream = GZIP_HEADER.to_vec();
+        let kept_len;
+        {
+            let level = flate2::Compression::best();
+            let mut encoder = flate2::write::DeflateEncoder::new(&mut stream, level);
+            encoder.write_all(b"kept").unwrap();
+            encoder.flush().unwrap();
+            kept_len = encoder.get_ref().len();
+            encoder.write_all(b"cut off").unwrap();
+        }
+        stream.truncate(kept_len);
+
+        let tail = crate::testutil::noise(70000, 1);
+        stored(&tail, &mut stream);
+
+        let mut expected = b"kept".to_vec();
+        expected.extend_from_slice(&tail);
+        expected.extend_from_slice(&[0; 1024]);
+
+        stream.extend_from_slice(&zeros_and_last(1024));
+        stream.extend_from_slice(&trailer(crc32fast::hash(&expected), expected.len() as u64));
+
+        /* GzDecoder checks the trailer */
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&stream[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+
+        assert_eq!(stored_size(70000), 70010);
+    }
+
+    #[test]
+    fn test_zeros_and_last_lengths() {
+        for len in [4, 5, 6, 259, 260, 261, 262, 1024, 100000] {
+            let mut decoded = Vec::new();
+            flate2::read::DeflateDecoder::new(&zeros_and_last(len)[..])
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, vec![0; len]);
+        }
+    }
+}
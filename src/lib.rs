@@ -0,0 +1,40 @@
+//! Create brotli compressed tar archives holding as many files as fit
+//! in a size budget.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use partial_tar_brotli::{Options, PartialArchiveBuilder};
+//!
+//! let output = std::fs::File::create_new("debug-dumps.tar.br")?;
+//! let mut builder = PartialArchiveBuilder::new(output, 16 * 1024 * 1024, Options::default());
+//! builder.add_path("debug-dumps/slowness-1724087161.json");
+//! builder.add_data("summary.txt", "everything is slow");
+//!
+//! let report = builder.finish()?;
+//! println!("{} bytes, {} files skipped", report.size, report.skipped().count());
+//! # Ok(())
+//! # }
+//! ```
+
+mod archive;
+mod bits;
+mod builder;
+mod checkpoint;
+mod compressor;
+mod cut;
+mod deflate_block;
+mod manifest;
+mod metablock;
+mod prepare;
+mod select;
+mod sink;
+mod tune;
+mod zstd_block;
+
+pub use builder::{
+    BrotliParams, Cut, EntryReport, EntryStatus, Format, Group, GroupReport, Objective, Options,
+    Order, Partial, PartialArchiveBuilder, Priority, Progress, Report, Share, SkipReason,
+};
+
+#[cfg(test)]
+mod testutil;
@@ -1,176 +1,457 @@
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{Seek, Write};
+use std::io::{IsTerminal, Read};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use flate2::read::GzDecoder;
+
+use partial_tar_brotli::{
+    BrotliParams, Cut, EntryStatus, Format, Group, Objective, Options, Order,
+    PartialArchiveBuilder, Priority, Progress, SkipReason,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    max_size: u64,
+    /// Budget in bytes. Given again with --output, writes another archive
+    /// within that budget, compressing the files only once for all
+    #[arg(short, long, required = true)]
+    max_size: Vec<u64>,
 
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 
-    #[arg(short, long)]
-    output: PathBuf,
+    /// Output file, or "-" for stdout, one for each --max-size
+    #[arg(short, long, required = true)]
+    output: Vec<PathBuf>,
 
     #[arg(long, default_value_t = false)]
     auto_decompress_gz: bool,
 
-    #[arg()]
-    files: Vec<PathBuf>,
-}
+    /// Keep trying the remaining files after one does not fit
+    #[arg(long, default_value_t = false)]
+    skip_oversized: bool,
 
-fn flush_and_get_position(
-    archive: &mut tar::Builder<brotli::CompressorWriter<&std::fs::File>>,
-) -> Result<u64> {
-    let compressor = archive.get_mut();
-    compressor.flush().context("Could not flush output")?;
+    /// Pass over files that a quick estimate shows can't fit, without
+    /// compressing them
+    #[arg(long, default_value_t = false)]
+    prescreen: bool,
+
+    /// Read files in N threads ahead of compressing them: decompress .gz
+    /// files for --auto-decompress-gz, hash the others and estimate them
+    /// for --prescreen
+    #[arg(short, long, value_name = "N", default_value_t = 0)]
+    jobs: usize,
+
+    /// Compression format: brotli, zstd, gzip or none
+    #[arg(long, default_value = "brotli")]
+    format: Format,
+
+    /// Brotli quality, from 0 (fastest) to 11 (smallest), or "auto" for
+    /// the fastest that is projected to fit the most files [default: 11]
+    #[arg(long)]
+    quality: Option<Quality>,
+
+    /// Lower the brotli quality until compressing is projected to take
+    /// at most SECS seconds
+    #[arg(long, value_name = "SECS")]
+    max_time: Option<f64>,
+
+    /// Brotli window size as a power of two, from 10 to 24, or up to 30
+    /// with --large-window [default: 22]
+    #[arg(long)]
+    lgwin: Option<u32>,
+
+    /// Use brotli's large window extension, decompress with e.g.
+    /// `brotli --large_window=30 -d`
+    #[arg(long, default_value_t = false)]
+    large_window: bool,
 
-    Ok(compressor.get_mut().stream_position().unwrap())
-}
+    /// Size in bytes of the brotli encoder's buffer [default: 4096]
+    #[arg(long)]
+    buffer_size: Option<usize>,
+
+    /// Only add files matching this glob pattern (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Leave out files matching this glob pattern (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
 
-fn add_manifest<W: Write>(args: &Args, archive: &mut tar::Builder<W>) -> Result<()> {
-    let manifest =
-        serde_json::to_value(&args.files).context("Could not create manifest")?.to_string();
-    let manifest_data = manifest.as_bytes();
+    /// Leave out files that .gitignore and .ignore files exclude
+    #[arg(long, default_value_t = false)]
+    respect_ignore_files: bool,
+
+    /// Order files are tried in: given, mtime-newest, mtime-oldest,
+    /// size-ascending, name, timestamp-newest or timestamp-oldest
+    #[arg(long, default_value = "given")]
+    order: Order,
+
+    /// Store the files that fit in this order instead, e.g. decide by
+    /// --order=mtime-newest but store mtime-oldest first
+    #[arg(long, value_name = "ORDER")]
+    write_order: Option<Order>,
+
+    /// Choose the files by estimating their compressed sizes first, to
+    /// maximize: count, bytes or priority (earlier in --order counts more)
+    #[arg(long, value_name = "OBJECTIVE")]
+    optimize: Option<Objective>,
+
+    /// Fail unless files matching this glob pattern fit (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    must_include: Vec<String>,
+
+    /// Try files matching GLOB by priority N, higher first, default 0
+    /// (can be repeated)
+    #[arg(long, value_name = "GLOB=N", value_parser = parse_priority)]
+    priority: Vec<(String, Priority)>,
+
+    /// Read further priorities from FILE, lines of "N GLOB" or "must GLOB"
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+
+    /// Give the files in group NAME a SHARE of the budget, in bytes or
+    /// as "N%". Members are glob patterns, directories, or "@FILE" for
+    /// the files listed in FILE (can be repeated)
+    #[arg(long, value_name = "NAME:SHARE:MEMBER[:MEMBER...]")]
+    group: Vec<String>,
+
+    /// Take turns between the groups instead of following --order
+    #[arg(long, default_value_t = false, requires = "group")]
+    round_robin: bool,
+
+    /// Store what fits of a file that does not fit whole, as FILE.partial,
+    /// cut at any byte, at line ends or keeping JSON files valid: bytes
+    /// (the default), lines or json
+    #[arg(long, value_name = "CUT", num_args = 0..=1, require_equals = true, default_missing_value = "bytes")]
+    partial: Option<Cut>,
+
+    /// Start a new archive when one is full, up to N of them, named like
+    /// out.001.tar.br, with an index of what went where in out.index.json
+    #[arg(long, value_name = "N", conflicts_with = "split")]
+    volumes: Option<usize>,
+
+    /// Like --volumes, as many as needed
+    #[arg(long, default_value_t = false)]
+    split: bool,
 
-    let mut header = tar::Header::new_gnu();
-    header.set_size(manifest_data.len() as u64);
-    header.set_mode(0o644);
+    /// Also add the files listed in FILE ("-" for stdin), one per line
+    #[arg(short = 'T', long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
 
-    archive
-        .append_data(&mut header, "partial-tar-brotli-manifest.json", manifest_data)
-        .context("Could not add manifest to archive")?;
+    /// Files listed by --files-from are separated by NUL characters
+    #[arg(long, default_value_t = false, requires = "files_from")]
+    null: bool,
 
-    Ok(())
+    /// Files, directories are added recursively
+    #[arg()]
+    files: Vec<PathBuf>,
 }
 
-fn generate_archive_filename(orig: &Path) -> PathBuf {
-    let mut res = std::path::PathBuf::new();
+/// Value of --quality.
+#[derive(Debug, Clone, Copy)]
+enum Quality {
+    Level(u32),
+    Auto,
+}
 
-    for component in orig.components() {
-        match component {
-            std::path::Component::Normal(part) => {
-                if !part.is_empty() {
-                    res.push(part);
-                }
-            }
-            std::path::Component::CurDir => (),
-            std::path::Component::RootDir => {
-                res.clear();
-            }
-            std::path::Component::ParentDir => {
-                res.pop();
+impl std::str::FromStr for Quality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Quality::Auto),
+            _ => {
+                Ok(Quality::Level(s.parse().with_context(|| {
+                    format!("Unknown quality {:?}, expected 0 to 11 or auto", s)
+                })?))
             }
-            std::path::Component::Prefix(_) => todo!(),
         }
     }
+}
 
-    res
+fn parse_priority(s: &str) -> Result<(String, Priority)> {
+    let (pattern, priority) = s.rsplit_once('=').context("Expected GLOB=N")?;
+    Ok((pattern.into(), priority.parse()?))
 }
 
-fn add_file_to_archive<W: Write>(
-    args: &Args,
-    archive: &mut tar::Builder<W>,
-    file: &Path,
-) -> Result<()> {
-    if args.auto_decompress_gz && file.extension().unwrap_or_default() == "gz" {
-        if args.verbose {
-            eprintln!("Decompressing {} for better compression.", file.display());
+/// Priority rules, one "PRIORITY GLOB" per line. Empty lines and lines
+/// starting with '#' are ignored.
+fn parse_rules(rules: &str) -> Result<Vec<(String, Priority)>> {
+    rules
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (priority, pattern) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("Expected \"PRIORITY GLOB\", got {:?}", line))?;
+            Ok((pattern.trim_start().into(), priority.parse()?))
+        })
+        .collect()
+}
+
+fn parse_group(group: &str) -> Result<Group> {
+    let mut parts = group.split(':');
+    let (Some(name), Some(share)) = (parts.next(), parts.next()) else {
+        bail!("Expected NAME:SHARE:MEMBER..., got {:?}", group);
+    };
+    let mut group =
+        Group { name: name.into(), share: share.parse()?, patterns: Vec::new(), paths: Vec::new() };
+
+    for member in parts {
+        if let Some(list) = member.strip_prefix('@') {
+            group.paths.extend(read_file_list(Path::new(list), false)?);
+        } else if Path::new(member).is_dir() {
+            group.paths.push(member.into());
+        } else {
+            group.patterns.push(member.into());
         }
+    }
+
+    Ok(group)
+}
 
-        let mut uncompressed = tempfile::tempfile()?;
-        let mut gz = GzDecoder::new(File::open(file)?);
+/// Paths in a list of files separated by `separator`, empty ones are
+/// ignored.
+fn parse_file_list(list: &[u8], separator: u8) -> Vec<PathBuf> {
+    list.split(|b| *b == separator)
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect()
+}
 
-        std::io::copy(&mut gz, &mut uncompressed)?;
-        uncompressed.seek(std::io::SeekFrom::Start(0)).expect("seek");
-        archive
-            .append_file(generate_archive_filename(&file.with_extension("")), &mut uncompressed)
-            .context("Could not add decompressed file to archive")?;
+fn read_file_list(list: &Path, null: bool) -> Result<Vec<PathBuf>> {
+    let mut data = Vec::new();
+    if list == Path::new("-") {
+        std::io::stdin().lock().read_to_end(&mut data).context("Could not read file list")?;
     } else {
-        archive
-            .append_path_with_name(file, generate_archive_filename(file))
-            .context("Could not add file to archive")?;
+        File::open(list)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .with_context(|| format!("Could not read file list {}", list.display()))?;
     }
 
-    Ok(())
+    Ok(parse_file_list(&data, if null { b'\0' } else { b'\n' }))
 }
 
-fn do_write(args: &Args) -> Result<()> {
-    let mut out = File::create_new(&args.output).context("Could not create output file")?;
-
-    let mut truncate_pos: Option<u64> = None;
-    let mut added = 0;
+/// `output` with `part` inserted before the ".tar" in its file name, or
+/// appended if there is none, e.g. "out.001.tar.br".
+fn volume_path(output: &Path, part: &str) -> PathBuf {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let name = match name.find(".tar") {
+        Some(i) => format!("{}.{}{}", &name[..i], part, &name[i..]),
+        None => format!("{}.{}", name, part),
+    };
+    output.with_file_name(name)
+}
 
-    let mut archive = tar::Builder::new(brotli::CompressorWriter::new(&out, 4096, 11, 22));
+/// "out.index.json" for `output` "out.tar.br".
+fn index_path(output: &Path) -> PathBuf {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.find(".tar").map_or(&name[..], |i| &name[..i]);
+    output.with_file_name(format!("{}.index.json", stem))
+}
 
-    /* Don't need irrelevant details like timestamp and owner/group */
-    archive.mode(tar::HeaderMode::Deterministic);
+fn stdout() -> Result<std::io::StdoutLock<'static>> {
+    let stdout = std::io::stdout().lock();
+    if stdout.is_terminal() {
+        bail!("Refusing to write compressed data to a terminal");
+    }
+    Ok(stdout)
+}
 
-    add_manifest(args, &mut archive)?;
+fn do_write(args: &Args) -> Result<()> {
+    if args.max_size.len() != args.output.len() {
+        bail!(
+            "Expected an --output for each --max-size, got {} and {}",
+            args.output.len(),
+            args.max_size.len()
+        );
+    }
+    if args.output.iter().filter(|o| *o == Path::new("-")).count() > 1 {
+        bail!("Only one output can be stdout");
+    }
+    let (max_size, output) = (args.max_size[0], &args.output[0]);
+
+    /* The first matching rule counts */
+    let mut priorities: Vec<_> =
+        args.must_include.iter().map(|pattern| (pattern.clone(), Priority::Must)).collect();
+    priorities.extend(args.priority.iter().cloned());
+    if let Some(rules) = &args.rules {
+        let rules = std::fs::read_to_string(rules)
+            .with_context(|| format!("Could not read rules {}", rules.display()))?;
+        priorities.extend(parse_rules(&rules)?);
+    }
 
-    for file in &args.files {
-        let before_pos = flush_and_get_position(&mut archive)?;
+    let mut files = args.files.clone();
+    if let Some(list) = &args.files_from {
+        files.extend(read_file_list(list, args.null)?);
+    }
 
-        add_file_to_archive(args, &mut archive, file)?;
+    let brotli = BrotliParams::default();
+    let brotli = BrotliParams {
+        quality: match args.quality {
+            Some(Quality::Level(quality)) => quality,
+            _ => brotli.quality,
+        },
+        lgwin: args.lgwin.unwrap_or(brotli.lgwin),
+        large_window: args.large_window,
+        buffer_size: args.buffer_size.unwrap_or(brotli.buffer_size),
+    };
+    let tuned = args.quality.is_some()
+        || args.max_time.is_some()
+        || args.lgwin.is_some()
+        || args.large_window
+        || args.buffer_size.is_some();
+    if tuned && args.format != Format::Brotli {
+        bail!(
+            "--quality, --max-time, --lgwin, --large-window and --buffer-size are only for brotli"
+        );
+    }
+    brotli.check()?;
 
-        let after_pos = flush_and_get_position(&mut archive)?;
-        if after_pos > args.max_size {
-            if args.verbose {
-                eprintln!("{} does not fit. Archive would be {} bytes.", file.display(), after_pos);
-            }
-            truncate_pos = Some(before_pos);
-            break;
+    let mut options = Options::default();
+    if args.verbose {
+        options.progress = Some(Progress::new(|message| eprintln!("{}", message)));
+    }
+    options.auto_decompress_gz = args.auto_decompress_gz;
+    options.skip_oversized = args.skip_oversized;
+    options.prescreen = args.prescreen;
+    options.jobs = args.jobs;
+    options.format = args.format;
+    options.brotli = brotli;
+    options.max_time = args
+        .max_time
+        .map(std::time::Duration::try_from_secs_f64)
+        .transpose()
+        .context("Invalid --max-time")?;
+    options.auto_quality = matches!(args.quality, Some(Quality::Auto));
+    options.include = args.include.clone();
+    options.exclude = args.exclude.clone();
+    options.respect_ignore_files = args.respect_ignore_files;
+    options.order = args.order;
+    options.write_order = args.write_order;
+    options.optimize = args.optimize;
+    options.priorities = priorities;
+    options.groups = args.group.iter().map(|g| parse_group(g)).collect::<Result<_>>()?;
+    options.round_robin = args.round_robin;
+    options.partial = args.partial;
+    let volumes = if args.split { Some(usize::MAX) } else { args.volumes };
+    let mut created = Vec::new();
+    let mut builder = if let Some(volumes) = volumes {
+        if output == Path::new("-") {
+            bail!("Volumes can't be written to stdout");
         }
-        added += 1;
-        if args.verbose {
-            eprintln!("{} (used {} bytes)", file.display(), after_pos - before_pos);
+        let create = |volume| {
+            let path = volume_path(output, &format!("{:03}", volume));
+            File::create_new(&path)
+                .with_context(|| format!("Could not create volume {}", path.display()))
+        };
+        PartialArchiveBuilder::new_volumes(create, volumes, max_size, options)
+    } else if output == Path::new("-") {
+        PartialArchiveBuilder::new_streaming(stdout()?, max_size, options)
+    } else {
+        let out = File::create_new(output).context("Could not create output file")?;
+        created.push(output.as_path());
+        PartialArchiveBuilder::new(out, max_size, options)
+    };
+    for (max_size, output) in args.max_size.iter().zip(&args.output).skip(1) {
+        if output == Path::new("-") {
+            builder.add_streaming_tier(stdout()?, *max_size);
+        } else {
+            let out = File::create_new(output).with_context(|| {
+                remove_empty(&created);
+                format!("Could not create output file {}", output.display())
+            })?;
+            created.push(output.as_path());
+            builder.add_tier(out, *max_size);
         }
     }
+    for file in files {
+        builder.add_path(file);
+    }
 
-    drop(archive);
-
-    if let Some(p) = truncate_pos {
-        /* Need to rewind (truncate) the archive to fit max-size.
-         *
-         * A flush() call has been made on the CompressorWriter so
-         * that this position always ends a metadata block (and that
-         * is at a byte boundary).
-         *
-         * All brotli files must end with a metadata block with the
-         * "ISLAST" flag set. CompressorWriter::drop writes that
-         * automatically, but that is lost when the file is
-         * truncated. So it must be written manually here. Luckily
-         * such empty last metadata block is really easy to write, as
-         * it always constitutes a byte whose first two bits set
-         * (ISLAST and ISLASTEMPTY). [RFC7932 9.2]
-         *
-         * Strictly speaking the tar file also should contain a end of
-         * file marker (two blocks filled with 0x00) but at least GNU
-         * tar ignores that (unless run with
-         * `--warning=missing-zero-blocks` option)
-         */
-        out.set_len(p).unwrap();
-        out.seek(std::io::SeekFrom::End(0)).expect("seek");
-        out.write(&[0b0000_0011]).context("Could not write last byte to archive")?;
+    let report = builder.finish().inspect_err(|_| remove_empty(&created))?;
+
+    let added = report.included().count();
+    if added < report.entries.len() {
         eprintln!(
             "Done! {} out of {} files added ({} skipped)",
             added,
-            args.files.len(),
-            args.files.len() - added
+            report.entries.len(),
+            report.entries.len() - added
         );
+        for entry in report.skipped() {
+            let reason = match entry.status {
+                EntryStatus::Skipped(SkipReason::DoesNotFit) => "Did not fit",
+                EntryStatus::Skipped(SkipReason::NotAttempted) => "Not attempted",
+                EntryStatus::Skipped(SkipReason::NotSelected) => "Not selected",
+                EntryStatus::Skipped(SkipReason::ScreenedOut) => "Screened out",
+                EntryStatus::Included { .. } => unreachable!(),
+            };
+            eprintln!("{}: {}", reason, entry.name.display());
+        }
     } else {
         eprintln!("Done! All {} files added to archive.", added);
     }
+    for entry in report.entries.iter() {
+        if let (Some(partial), EntryStatus::Included { .. }) = (&entry.partial, &entry.status) {
+            let stored: u64 = partial.kept.iter().map(|range| range.end - range.start).sum();
+            let cut = match partial.cut {
+                Cut::Bytes => "",
+                Cut::Lines => ", whole lines",
+                Cut::Json => ", as valid JSON",
+            };
+            eprintln!("Partially added: {} ({} bytes kept{})", entry.name.display(), stored, cut);
+        }
+    }
+    for (volume, size) in (1..).zip(&report.volumes) {
+        let added = report.entries.iter().filter(|e| e.volume == Some(volume)).count();
+        eprintln!(
+            "Volume {}: {} files, {} bytes",
+            volume_path(output, &format!("{:03}", volume)).display(),
+            added,
+            size
+        );
+    }
+    if volumes.is_some() {
+        let index = index_path(output);
+        std::fs::write(&index, report.index())
+            .with_context(|| format!("Could not write index {}", index.display()))?;
+    }
+    for (tier, output) in report.tiers.iter().zip(args.output.iter().skip(1)) {
+        eprintln!(
+            "Also {}: {} out of {} files added, {} bytes",
+            output.display(),
+            tier.included().count(),
+            tier.entries.len(),
+            tier.size
+        );
+    }
+    for group in &report.groups {
+        eprintln!(
+            "Group {}: {} of {} bytes used, {} files added, {} skipped",
+            group.name, group.used, group.quota, group.included, group.skipped
+        );
+    }
 
     Ok(())
 }
 
+/// Removes the outputs nothing was written to, so that a run that
+/// failed before writing can be repeated. Archives that were written
+/// (like one missing a must-include file) are kept.
+fn remove_empty(outputs: &[&Path]) {
+    for output in outputs {
+        if std::fs::metadata(output).is_ok_and(|m| m.len() == 0) {
+            let _ = std::fs::remove_file(output);
+        }
+    }
+}
+
 fn main() {
     if let Err(e) = do_write(&Args::parse()) {
         eprintln!("Error: {:?}", e);
@@ -181,32 +462,70 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::ffi::OsString;
+    use partial_tar_brotli::Share;
 
     #[test]
-    fn test_generate_archive_filename() {
-        fn check(orig: &str, exp: &str) {
-            let p: PathBuf = orig.into();
-            let expected: OsString = exp.into();
+    fn test_parse_rules() {
+        let rules = "# crash bundles\nmust  */summary.json\n\n10 *.json\n-5 logs/**\n";
+        assert_eq!(
+            parse_rules(rules).unwrap(),
+            [
+                ("*/summary.json".into(), Priority::Must),
+                ("*.json".into(), Priority::Weight(10)),
+                ("logs/**".into(), Priority::Weight(-5)),
+            ]
+        );
+        assert!(parse_rules("must").is_err());
+        assert!(parse_rules("high *.json").is_err());
 
-            let res = generate_archive_filename(&p);
-            assert_eq!(res.as_os_str(), expected);
+        assert_eq!(parse_priority("a=b=3").unwrap(), ("a=b".into(), Priority::Weight(3)));
+    }
 
-            // double normalization gives same
-            assert_eq!(generate_archive_filename(&res), expected);
-        }
-        fn check_unchanged(path: &str) {
-            check(path, path);
-        }
+    #[test]
+    fn test_parse_group() {
+        let group = parse_group("api:40%:api/**:*.api.json").unwrap();
+        assert_eq!(group.name, "api");
+        assert_eq!(group.share, Share::Percent(40));
+        assert_eq!(group.patterns, ["api/**", "*.api.json"]);
+
+        let group = parse_group("src:1000000:src").unwrap();
+        assert_eq!(group.share, Share::Bytes(1000000));
+        assert_eq!(group.paths, [PathBuf::from("src")]);
+
+        assert!(parse_group("api").is_err());
+        assert!(parse_group("api:lots:api/**").is_err());
+    }
 
-        check_unchanged("test.txt");
-        check_unchanged("foo/test.txt");
-        check("foo//test.txt", "foo/test.txt");
-        check("foo/test.txt//", "foo/test.txt");
-        check("../some/file", "some/file");
-        check("some/file/buried/../deep/down", "some/file/deep/down");
-        check("/file/with/absolute/path", "file/with/absolute/path");
-        check("/file/with/absolute/../path", "file/with/path");
-        check("/../../crazy", "crazy");
+    #[test]
+    fn test_volume_path() {
+        assert_eq!(
+            volume_path(Path::new("out/dumps.tar.br"), "001"),
+            Path::new("out/dumps.001.tar.br")
+        );
+        assert_eq!(volume_path(Path::new("dumps.br"), "002"), Path::new("dumps.br.002"));
+        assert_eq!(index_path(Path::new("out/dumps.tar.br")), Path::new("out/dumps.index.json"));
+    }
+
+    #[test]
+    fn test_parse_file_list() {
+        assert_eq!(
+            parse_file_list(b"a.json\nb c.json\n\nd.json", b'\n'),
+            [PathBuf::from("a.json"), PathBuf::from("b c.json"), PathBuf::from("d.json")]
+        );
+        assert_eq!(
+            parse_file_list(b"new\nline.json\0e.json\0", b'\0'),
+            [PathBuf::from("new\nline.json"), PathBuf::from("e.json")]
+        );
+    }
+
+    #[test]
+    fn test_remove_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let (empty, written) = (dir.path().join("a.tar.br"), dir.path().join("b.tar.br"));
+        std::fs::write(&empty, "").unwrap();
+        std::fs::write(&written, "archive").unwrap();
+        remove_empty(&[&empty, &written, &dir.path().join("c.tar.br")]);
+        assert!(!empty.exists());
+        assert!(written.exists());
     }
 }
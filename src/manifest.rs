@@ -0,0 +1,443 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::archive::decompresses;
+use crate::builder::{
+    Cut, EntryReport, EntryStatus, GroupReport, Options, Partial, Priority, Report, SkipReason,
+};
+
+/// Bumped whenever readers of the manifest need to know about a change.
+pub(crate) const SCHEMA_VERSION: u64 = 3;
+
+fn reason_str(reason: SkipReason) -> &'static str {
+    match reason {
+        SkipReason::DoesNotFit => "does-not-fit",
+        SkipReason::NotAttempted => "not-attempted",
+        SkipReason::NotSelected => "not-selected",
+        SkipReason::ScreenedOut => "screened-out",
+    }
+}
+
+fn cut_str(cut: Cut) -> &'static str {
+    match cut {
+        Cut::Bytes => "bytes",
+        Cut::Lines => "lines",
+        Cut::Json => "json",
+    }
+}
+
+fn entry_json(entry: &EntryReport) -> Value {
+    let mut fields = Map::new();
+
+    if let Some(source) = &entry.source {
+        fields.insert("source".into(), source.to_string_lossy().into());
+    }
+    fields.insert("name".into(), entry.name.to_string_lossy().into());
+    fields.insert("size".into(), entry.size.into());
+    if let Some(size) = entry.decompressed_size {
+        fields.insert("decompressed_size".into(), size.into());
+    }
+    if let Some(size) = entry.estimated_size {
+        fields.insert("estimated_size".into(), size.into());
+    }
+    if let Some(size) = entry.screened_size {
+        fields.insert("screened_size".into(), size.into());
+    }
+    if let Some(mtime) = entry.mtime {
+        fields.insert("mtime".into(), mtime.into());
+    }
+    /* Only for contents that were stored */
+    let included = matches!(entry.status, EntryStatus::Included { .. });
+    if let Some(sha256) = entry.sha256.as_ref().filter(|_| included) {
+        fields.insert("sha256".into(), sha256.as_str().into());
+    }
+    if let Some(volume) = entry.volume {
+        fields.insert("volume".into(), volume.into());
+    }
+    if let Some(group) = &entry.group {
+        fields.insert("group".into(), group.as_str().into());
+    }
+    if let Some(partial) = &entry.partial {
+        let kept: Vec<_> =
+            partial.kept.iter().map(|range| json!([range.start, range.end])).collect();
+        fields.insert("kept".into(), kept.into());
+        fields.insert("cut".into(), cut_str(partial.cut).into());
+    }
+    match entry.priority {
+        Priority::Weight(0) => {}
+        Priority::Weight(weight) => {
+            fields.insert("priority".into(), weight.into());
+        }
+        Priority::Must => {
+            fields.insert("priority".into(), "must".into());
+        }
+    }
+    match entry.status {
+        EntryStatus::Included { compressed_size } => {
+            fields.insert("compressed_size".into(), compressed_size.into());
+        }
+        EntryStatus::Skipped(reason) => {
+            fields.insert("reason".into(), reason_str(reason).into());
+        }
+    }
+
+    fields.into()
+}
+
+/// Longest [`entry_json`] `entry` can get with `options` in an archive
+/// of `max_size` bytes, skipped and at all.
+fn max_entry_len(entry: &EntryReport, options: &Options, max_size: u64) -> (usize, usize) {
+    let longest_reason = [
+        SkipReason::DoesNotFit,
+        SkipReason::NotAttempted,
+        SkipReason::NotSelected,
+        SkipReason::ScreenedOut,
+    ]
+    .into_iter()
+    .max_by_key(|r| reason_str(*r).len())
+    .unwrap();
+
+    /* Going by the size the input has before packing, a decompressed
+     * size could be anything. Any estimate of the compressed size stays
+     * below twice the size. */
+    let unknown = entry.source.as_deref().is_some_and(|source| decompresses(options, source));
+    let stored = if unknown { u64::MAX } else { entry.size };
+    let estimated = stored.saturating_mul(2).saturating_add(1 << 16);
+
+    let mut worst = EntryReport {
+        status: EntryStatus::Skipped(longest_reason),
+        sha256: None,
+        partial: None,
+        ..entry.clone()
+    };
+    if unknown {
+        worst.decompressed_size = Some(u64::MAX);
+    }
+    if options.optimize.is_some() {
+        worst.estimated_size = Some(estimated);
+    }
+    if options.prescreen {
+        worst.screened_size = Some(estimated);
+    }
+    let skipped = entry_json(&worst).to_string().len();
+
+    if options.partial.is_some() {
+        let mut partial = worst.name.clone().into_os_string();
+        partial.push(".partial");
+        worst.name = partial.into();
+        let longest_cut =
+            [Cut::Bytes, Cut::Lines, Cut::Json].into_iter().max_by_key(|c| cut_str(*c).len());
+        worst.partial = Some(Partial {
+            kept: vec![stored..stored, stored..stored],
+            cut: longest_cut.unwrap(),
+            closing: Vec::new(),
+        });
+    }
+    worst.sha256 = Some("0".repeat(64));
+    worst.status = EntryStatus::Included { compressed_size: max_size };
+    let included = entry_json(&worst).to_string().len();
+
+    (skipped, std::cmp::max(skipped, included))
+}
+
+/// Upper bound of the length of [`Manifest::to_json`] while packing,
+/// tightened as the entries settle. Entries not settled yet are counted
+/// as the longest they can get skipped, but for one being tried, which
+/// may be included.
+pub(crate) struct ManifestBound {
+    len: usize,
+    /// How long each entry can get listed skipped, and at all.
+    worst: Vec<(usize, usize)>,
+    summarised: bool,
+}
+
+impl ManifestBound {
+    /// The bound with no entry tried.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The bound while `entries[i]` is tried.
+    pub(crate) fn trying(&self, i: usize) -> usize {
+        let (skipped, any) = self.worst[i];
+        self.len - skipped + any
+    }
+
+    /// How much longer the manifest can get with `entries[i]` included.
+    pub(crate) fn growth(&self, i: usize) -> usize {
+        let (skipped, any) = self.worst[i];
+        any - skipped
+    }
+
+    /// Takes `entry` as `entries[i]` ends up, once nothing changes
+    /// about it any more.
+    pub(crate) fn settle(&mut self, i: usize, entry: &EntryReport) {
+        let len = listed_len(entry, self.summarised);
+        let (skipped, any) = &mut self.worst[i];
+        self.len = self.len - *skipped + len;
+        (*skipped, *any) = (len, len);
+    }
+}
+
+/// Length `entry` adds to the manifest, with its separator.
+fn listed_len(entry: &EntryReport, summarised: bool) -> usize {
+    match entry.status {
+        EntryStatus::Skipped(_) if summarised => 0,
+        _ => entry_json(entry).to_string().len() + 1,
+    }
+}
+
+/// The manifest describing what ended up in the archive.
+///
+/// Archive wide information is gathered when created, the entries are
+/// filled in by [`to_json`](Self::to_json) once packing is done.
+pub(crate) struct Manifest {
+    fields: Map<String, Value>,
+    /// Names and quotas of the groups, quotas are set once known.
+    groups: Vec<(String, u64)>,
+    options: Options,
+    max_size: u64,
+    /// Skipped entries are only counted, see [`bound`](Self::bound).
+    summarised: bool,
+}
+
+impl Manifest {
+    /// `compression` describes the format and its parameters.
+    pub(crate) fn new(max_size: u64, compression: Value, options: &Options) -> Self {
+        let created_at =
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        let header = json!({
+            "schema_version": SCHEMA_VERSION,
+            "tool": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "created_at": created_at,
+            "host": gethostname::gethostname().to_string_lossy(),
+            "budget": max_size,
+            "compression": compression,
+        });
+
+        let Value::Object(mut fields) = header else { unreachable!() };
+        if options.max_time.is_some() || options.auto_quality {
+            let tuning = json!({
+                "max_time": options.max_time.map(|t| t.as_secs_f64()),
+                "auto": options.auto_quality,
+            });
+            fields.insert("quality_chosen_by".into(), tuning);
+        }
+        let groups = options.groups.iter().map(|g| (g.name.clone(), 0)).collect();
+        Manifest { fields, groups, options: options.clone(), max_size, summarised: false }
+    }
+
+    pub(crate) fn set_volume(&mut self, volume: usize) {
+        self.fields.insert("volume".into(), volume.into());
+    }
+
+    pub(crate) fn set_quotas(&mut self, quotas: &[u64]) {
+        for ((_, quota), new) in self.groups.iter_mut().zip(quotas) {
+            *quota = *new;
+        }
+    }
+
+    pub(crate) fn to_json(&self, entries: &[EntryReport]) -> String {
+        let mut included = Vec::new();
+        let mut skipped = Vec::new();
+
+        let (mut unlisted, mut unlisted_size) = (0, 0);
+        for entry in entries {
+            match entry.status {
+                EntryStatus::Included { .. } => included.push(entry_json(entry)),
+                EntryStatus::Skipped(_) if self.summarised => {
+                    unlisted += 1;
+                    unlisted_size += entry.size;
+                }
+                EntryStatus::Skipped(_) => skipped.push(entry_json(entry)),
+            }
+        }
+
+        let mut fields = self.fields.clone();
+        fields.insert("included".into(), included.into());
+        fields.insert("skipped".into(), skipped.into());
+        if self.summarised {
+            let unlisted = json!({"count": unlisted, "size": unlisted_size});
+            fields.insert("skipped_unlisted".into(), unlisted);
+        }
+        if !self.groups.is_empty() {
+            let groups: Vec<_> = self
+                .groups
+                .iter()
+                .map(|(name, quota)| {
+                    let GroupReport { name, quota, used, included, skipped } =
+                        GroupReport::new(name, *quota, entries);
+                    json!({
+                        "name": name,
+                        "quota": quota,
+                        "used": used,
+                        "included": included,
+                        "skipped": skipped,
+                    })
+                })
+                .collect();
+            fields.insert("groups".into(), groups.into());
+        }
+
+        Value::from(fields).to_string()
+    }
+
+    /// Starts bounding the length of [`to_json`](Self::to_json) for
+    /// `entries` as they are before packing. Should listing all of them
+    /// skipped take more than half the budget, the skipped entries are
+    /// only counted from here on.
+    pub(crate) fn bound(&mut self, entries: &[EntryReport]) -> ManifestBound {
+        let mut worst: Vec<_> = entries
+            .iter()
+            .map(|e| max_entry_len(e, &self.options, self.max_size))
+            .map(|(skipped, any)| (skipped + 1, any + 1))
+            .collect();
+        let listed: usize = worst.iter().map(|(skipped, _)| skipped).sum();
+        self.summarised = listed as u64 > self.max_size / 2;
+        if self.summarised {
+            for (skipped, _) in &mut worst {
+                *skipped = 0;
+            }
+        }
+
+        /* Any quota and usage, counts up to the number of entries */
+        let counts = 2 * entries.len().to_string().len();
+        let groups = self.groups.len() * 2 * (u64::MAX.to_string().len() + counts);
+        /* The count and size of unlisted entries */
+        let unlisted = if self.summarised { 2 * u64::MAX.to_string().len() } else { 0 };
+        let len = self.to_json(&[]).len()
+            + worst.iter().map(|(skipped, _)| skipped).sum::<usize>()
+            + groups
+            + unlisted;
+        ManifestBound { len, worst, summarised: self.summarised }
+    }
+}
+
+/// Index of the volumes of `report` and the inputs stored in each.
+pub(crate) fn index_json(report: &Report) -> String {
+    let volumes: Vec<_> = (1..)
+        .zip(&report.volumes)
+        .map(|(volume, size)| {
+            let included: Vec<_> =
+                report.included().filter(|e| e.volume == Some(volume)).map(entry_json).collect();
+            json!({"volume": volume, "size": size, "included": included})
+        })
+        .collect();
+    let skipped: Vec<_> = report.skipped().map(entry_json).collect();
+
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "volumes": volumes,
+        "skipped": skipped,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn entry(name: &str, status: EntryStatus) -> EntryReport {
+        EntryReport {
+            mtime: Some(1724087161),
+            status,
+            ..EntryReport::new(Some(PathBuf::from(name)), PathBuf::from(name), 4711)
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let group = crate::Group {
+            name: "dumps".into(),
+            share: crate::Share::Percent(50),
+            patterns: vec!["c.*".into()],
+            paths: Vec::new(),
+        };
+        let options = Options {
+            optimize: Some(crate::Objective::Count),
+            groups: vec![group],
+            partial: Some(Cut::Lines),
+            auto_decompress_gz: true,
+            ..Default::default()
+        };
+        let mut manifest = Manifest::new(16777216, json!({"format": "brotli"}), &options);
+        let mut before = [
+            entry("a\"quoted\".json", EntryStatus::Skipped(SkipReason::NotAttempted)),
+            entry("b.json.gz", EntryStatus::Skipped(SkipReason::NotAttempted)),
+            entry("c.json", EntryStatus::Skipped(SkipReason::NotAttempted)),
+        ];
+
+        before[2].priority = Priority::Weight(-3);
+        before[2].group = Some("dumps".into());
+
+        let mut after = before.clone();
+        after[0].status = EntryStatus::Included { compressed_size: 12345 };
+        after[0].sha256 = Some("ab".repeat(32));
+        after[1].status = EntryStatus::Skipped(SkipReason::DoesNotFit);
+        after[1].name = PathBuf::from("b.json");
+        after[1].decompressed_size = Some(1234567890);
+        after[1].sha256 = Some("cd".repeat(32));
+        after[0].name = PathBuf::from("a\"quoted\".json.partial");
+        after[0].partial =
+            Some(Partial { kept: vec![0..100, 4611..4711], cut: Cut::Lines, closing: Vec::new() });
+        after[2].status = EntryStatus::Skipped(SkipReason::NotSelected);
+        after[2].estimated_size = Some(2345);
+
+        let mut bound = manifest.bound(&before);
+        manifest.set_quotas(&[8388000]);
+        let json = manifest.to_json(&after);
+        assert!(json.len() <= bound.trying(0));
+        /* Not as if any size could be up to u64::MAX */
+        assert!(bound.trying(0) < 2 * json.len());
+        for (i, entry) in after.iter().enumerate() {
+            bound.settle(i, entry);
+        }
+        assert!(json.len() <= bound.len());
+
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["schema_version"], SCHEMA_VERSION);
+        assert_eq!(parsed["budget"], 16777216);
+        assert_eq!(parsed["compression"]["format"], "brotli");
+        assert_eq!(
+            parsed["included"],
+            json!([{
+                "source": "a\"quoted\".json",
+                "name": "a\"quoted\".json.partial",
+                "size": 4711,
+                "mtime": 1724087161,
+                "sha256": "ab".repeat(32),
+                "kept": [[0, 100], [4611, 4711]],
+                "cut": "lines",
+                "compressed_size": 12345,
+            }])
+        );
+        assert_eq!(parsed["skipped"][0]["reason"], "does-not-fit");
+        assert_eq!(parsed["skipped"][0]["decompressed_size"], 1234567890);
+        assert!(parsed["skipped"][0].get("sha256").is_none());
+        assert_eq!(parsed["skipped"][1]["reason"], "not-selected");
+        assert_eq!(parsed["skipped"][1]["estimated_size"], 2345);
+        assert_eq!(parsed["skipped"][1]["priority"], -3);
+        assert_eq!(parsed["skipped"][1]["group"], "dumps");
+        assert_eq!(
+            parsed["groups"],
+            json!([{"name": "dumps", "quota": 8388000, "used": 0, "included": 0, "skipped": 1}])
+        );
+        assert!(parsed.get("skipped_unlisted").is_none());
+
+        /* Listing every entry would take most of a small budget */
+        let mut manifest = Manifest::new(500, json!({"format": "brotli"}), &options);
+        let bound = manifest.bound(&before);
+        let json = manifest.to_json(&after);
+        assert!(json.len() <= bound.trying(0));
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["included"].as_array().unwrap().len(), 1);
+        assert_eq!(parsed["skipped"], json!([]));
+        assert_eq!(parsed["skipped_unlisted"], json!({"count": 2, "size": 2 * 4711}));
+    }
+}
@@ -0,0 +1,169 @@
+//! Brotli metadata blocks written by hand, used to complete a stream
+//! that has been cut back to a checkpoint [RFC7932].
+//!
+//! Neither kind of block depends on the encoder state at the
+//! checkpoint: they don't refer to earlier data, and use a single
+//! prefix code for all literals whatever the context.
+
+use crate::bits::BitWriter;
+
+/// Largest uncompressed metadata block that has a 3 byte header.
+const STORED_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Simple prefix code with a single symbol, which then takes 0 bits to
+/// encode [RFC7932 3.4].
+fn write_single_symbol_code(w: &mut BitWriter, alphabet_bits: usize, symbol: u32) {
+    w.write(2, 1); // HSKIP 1: simple prefix code
+    w.write(2, 0); // NSYM - 1
+    w.write(alphabet_bits, symbol);
+}
+
+/// Appends `data` as uncompressed metadata blocks. Each has a 3 byte
+/// header: ISLAST (0), MNIBBLES (0 meaning 4 nibbles), MLEN-1 (16
+/// bits), ISUNCOMPRESSED (1), padded to a byte boundary.
+pub(crate) fn stored(data: &[u8], out: &mut Vec<u8>) {
+    for chunk in data.chunks(STORED_BLOCK_SIZE) {
+        let header = ((chunk.len() as u32 - 1) << 3) | (1 << 19);
+        out.extend_from_slice(&header.to_le_bytes()[..3]);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Number of bytes [`stored`] appends for `len` bytes of data.
+pub(crate) fn stored_size(len: u64) -> u64 {
+    len + 3 * len.div_ceil(STORED_BLOCK_SIZE as u64)
+}
+
+/* Copy length codes 0..=23 as (first length, extra bits) [RFC7932 5] */
+const COPY_LENGTHS: [(u32, usize); 24] = [
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 1),
+    (12, 1),
+    (14, 2),
+    (18, 2),
+    (22, 3),
+    (30, 3),
+    (38, 4),
+    (54, 4),
+    (70, 5),
+    (102, 5),
+    (134, 6),
+    (198, 7),
+    (326, 8),
+    (582, 9),
+    (1094, 10),
+    (2118, 24),
+];
+
+/// A compressed metadata block of `len` (71..=65536) zero bytes, followed
+/// by the empty last metadata block that ends the stream.
+///
+/// The block holds a single command: insert one literal zero and copy
+/// `len - 1` bytes from distance 1.
+pub(crate) fn zeros_and_last(len: usize) -> Vec<u8> {
+    assert!((71..=65536).contains(&len));
+
+    let copy_len = (len - 1) as u32;
+    let copy_code = COPY_LENGTHS.iter().rposition(|(first, _)| *first <= copy_len).unwrap();
+    let (copy_first, copy_extra_bits) = COPY_LENGTHS[copy_code];
+
+    let mut w = BitWriter::new();
+
+    /* Header [RFC7932 9.2] */
+    w.write(1, 0); // ISLAST
+    w.write(2, 0); // MNIBBLES: 4
+    w.write(16, len as u32 - 1); // MLEN - 1
+    w.write(1, 0); // ISUNCOMPRESSED
+    w.write(1, 0); // NBLTYPESL: 1
+    w.write(1, 0); // NBLTYPESI: 1
+    w.write(1, 0); // NBLTYPESD: 1
+    w.write(2, 0); // NPOSTFIX
+    w.write(4, 0); // NDIRECT
+    w.write(2, 0); // CMODE of the literal block type
+    w.write(1, 0); // NTREESL: 1
+    w.write(1, 0); // NTREESD: 1
+
+    /* The prefix codes, each with the only symbol used */
+    write_single_symbol_code(&mut w, 8, 0); // literal 0x00
+
+    /* Insert length code 1 (one literal), copy length code >= 16 and an
+     * explicit distance puts the command in the 384..447 range
+     * [RFC7932 5] */
+    write_single_symbol_code(&mut w, 10, 384 + (1 << 3) + (copy_code as u32 - 16));
+
+    /* Distance code 16 with NPOSTFIX 0 and NDIRECT 0 has one extra bit,
+     * 0 giving distance 1 [RFC7932 4] */
+    write_single_symbol_code(&mut w, 6, 16);
+
+    /* The command: insert length and copy length extra bits, the
+     * literal (0 bits), distance extra bits */
+    w.write(copy_extra_bits, copy_len - copy_first);
+    w.write(1, 0);
+
+    w.write(1, 1); // ISLAST
+    w.write(1, 1); // ISLASTEMPTY
+
+    w.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// A brotli stream holding `kept`, cut back to right after it.
+    fn truncated_stream(kept: &[u8]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        let kept_len;
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut stream, 4096, 11, 22);
+            encoder.write_all(kept).unwrap();
+            encoder.flush().unwrap();
+            kept_len = encoder.get_ref().len();
+            encoder.write_all(b"cut off").unwrap();
+        }
+        stream.truncate(kept_len);
+        stream
+    }
+
+    fn decode(stream: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(stream, 4096).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn test_complete_truncated_stream() {
+        let mut stream = truncated_stream(b"kept");
+
+        let tail = crate::testutil::noise(70000, 1);
+        stored(&tail, &mut stream);
+        stream.extend_from_slice(&zeros_and_last(1024));
+
+        let mut expected = b"kept".to_vec();
+        expected.extend_from_slice(&tail);
+        expected.extend_from_slice(&[0; 1024]);
+        assert_eq!(decode(&stream), expected);
+
+        assert_eq!(stored_size(70000), 70006);
+    }
+
+    #[test]
+    fn test_zeros_and_last_lengths() {
+        for len in [71, 100, 1024, 4096, 65536] {
+            let mut stream = truncated_stream(b"x");
+            stream.extend_from_slice(&zeros_and_last(len));
+
+            let mut expected = b"x".to_vec();
+            expected.resize(len + 1, 0);
+            assert_eq!(decode(&stream), expected);
+        }
+    }
+}
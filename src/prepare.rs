@@ -0,0 +1,270 @@
+//! Reading files in worker threads while the archive is compressed, see
+//! [`Options::jobs`].
+
+use std::cell::OnceCell;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use anyhow::Result;
+
+use crate::archive::{open_contents, prepare_file, Prepared};
+use crate::builder::{screen_contents, Options};
+
+/// Files held prepared at a time, per worker.
+const HELD_PER_JOB: usize = 2;
+
+/// Each file is prepared by a worker, or by whoever needs it first.
+#[derive(Default)]
+enum Job {
+    #[default]
+    Waiting,
+    Working,
+    Done(Result<Prepared>),
+    /// Taken, or released before it was.
+    Taken,
+}
+
+struct State {
+    jobs: Vec<Job>,
+    /// Files prepared and not released yet, or being prepared.
+    held: usize,
+    /// Nothing is taken any more.
+    closed: bool,
+}
+
+struct Shared {
+    options: Options,
+    paths: Vec<PathBuf>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn work(&self) {
+        let mut state = self.lock();
+        loop {
+            let limit = self.options.jobs * HELD_PER_JOB;
+            let next = state.jobs.iter().position(|job| matches!(job, Job::Waiting));
+            let i = match next {
+                _ if state.closed => return,
+                None => return,
+                Some(_) if state.held >= limit => {
+                    state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+                Some(i) => i,
+            };
+            state.jobs[i] = Job::Working;
+            state.held += 1;
+            drop(state);
+
+            let prepared = self.prepare(&self.paths[i]);
+            state = self.lock();
+            /* Dropped right away if released meanwhile */
+            let released = !matches!(state.jobs[i], Job::Working);
+            if prepared.is_err() || released {
+                state.held -= 1;
+            }
+            if !released {
+                state.jobs[i] = Job::Done(prepared);
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Prepares `path`, with its screening estimate when prescreening.
+    fn prepare(&self, path: &Path) -> Result<Prepared> {
+        let options = &self.options;
+        let mut prepared = prepare_file(options, path)?;
+        if options.prescreen {
+            let open_at = |offset| open_contents(options, prepared.path(), offset);
+            prepared.screened_size = Some(screen_contents(options, prepared.size, open_at)?);
+        }
+        Ok(prepared)
+    }
+}
+
+/// Stops the workers once every [`Pending`] file is dropped.
+struct Handle(Arc<Shared>);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// A file that is prepared in the background, or when first needed if
+/// there are no workers.
+pub(crate) struct Pending {
+    handle: Arc<Handle>,
+    index: usize,
+    prepared: OnceCell<Prepared>,
+}
+
+impl Pending {
+    pub(crate) fn path(&self) -> &Path {
+        &self.handle.0.paths[self.index]
+    }
+
+    /// The file as prepared by a worker, waiting for it if one is on it.
+    /// `None` if no worker got to it (nor will).
+    pub(crate) fn ready(&self) -> Result<Option<&Prepared>> {
+        if let Some(prepared) = self.prepared.get() {
+            return Ok(Some(prepared));
+        }
+
+        let shared = &self.handle.0;
+        let mut state = shared.lock();
+        loop {
+            match std::mem::replace(&mut state.jobs[self.index], Job::Taken) {
+                Job::Waiting | Job::Taken => return Ok(None),
+                Job::Working => {
+                    state.jobs[self.index] = Job::Working;
+                    state = shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                Job::Done(prepared) => {
+                    let prepared = prepared?;
+                    return Ok(Some(self.prepared.get_or_init(|| prepared)));
+                }
+            }
+        }
+    }
+
+    /// The prepared file, like [`ready`](Self::ready), but preparing it
+    /// here if no worker did (again, once released).
+    pub(crate) fn get(&self) -> Result<&Prepared> {
+        if let Some(prepared) = self.ready()? {
+            return Ok(prepared);
+        }
+        let shared = &self.handle.0;
+        let prepared = prepare_file(&shared.options, self.path())?;
+        shared.lock().held += 1;
+        Ok(self.prepared.get_or_init(|| prepared))
+    }
+
+    /// Deletes what was prepared, making room for the workers to prepare
+    /// more files. A file a worker prepared that was not taken yet, or is
+    /// still being prepared, is not waited for.
+    pub(crate) fn release(&mut self) {
+        let shared = &self.handle.0;
+        let mut state = shared.lock();
+        let job = std::mem::replace(&mut state.jobs[self.index], Job::Taken);
+        /* One still being prepared is dropped by its worker once done */
+        let held = match &job {
+            Job::Done(Ok(_)) => true,
+            Job::Taken => self.prepared.get().is_some(),
+            Job::Waiting | Job::Working | Job::Done(Err(_)) => false,
+        };
+        let prepared = self.prepared.take();
+        if held {
+            state.held -= 1;
+            shared.changed.notify_all();
+        }
+        drop(state);
+        drop((job, prepared));
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Starts [`Options::jobs`] workers preparing `paths` in order, with at
+/// most [`HELD_PER_JOB`] files held per worker at a time.
+pub(crate) fn prepare_ahead(options: &Options, paths: Vec<PathBuf>) -> Vec<Pending> {
+    let shared = Arc::new(Shared {
+        options: options.clone(),
+        state: Mutex::new(State {
+            jobs: paths.iter().map(|_| Job::Waiting).collect(),
+            held: 0,
+            closed: false,
+        }),
+        paths,
+        changed: Condvar::new(),
+    });
+    for _ in 0..options.jobs {
+        let shared = shared.clone();
+        std::thread::spawn(move || shared.work());
+    }
+
+    let handle = Arc::new(Handle(shared));
+    (0..handle.0.paths.len())
+        .map(|index| Pending { handle: handle.clone(), index, prepared: OnceCell::new() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_held_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..8)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.gz", i));
+                let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                gz.write_all(&vec![i as u8; 1000 * i]).unwrap();
+                std::fs::write(&path, gz.finish().unwrap()).unwrap();
+                path
+            })
+            .collect();
+        let options = Options { jobs: 2, auto_decompress_gz: true, ..Default::default() };
+
+        let mut pending = prepare_ahead(&options, paths);
+        let shared = pending[0].handle.0.clone();
+        for (i, file) in pending.iter_mut().enumerate() {
+            assert_eq!(file.get().unwrap().decompressed_size(), Some(1000 * i as u64));
+            /* Besides the workers' files, one may have been prepared here */
+            assert!(shared.lock().held <= 2 * HELD_PER_JOB + 1);
+            file.release();
+        }
+        assert_eq!(shared.lock().held, 0);
+
+        /* Prepared again once released */
+        assert_eq!(pending[3].get().unwrap().size, 3000);
+        assert!(pending[3].get().unwrap().path().exists());
+    }
+
+    fn wait_for(shared: &Shared, done: impl Fn(&State) -> bool) -> bool {
+        let timeout = std::time::Duration::from_secs(10);
+        let state = shared.lock();
+        let (state, _) = shared.changed.wait_timeout_while(state, timeout, |s| !done(s)).unwrap();
+        done(&state)
+    }
+
+    #[test]
+    fn test_released_untaken() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..8)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.log", i));
+                std::fs::write(&path, format!("line {}\n", i).repeat(1000)).unwrap();
+                path
+            })
+            .collect();
+        let options = Options { jobs: 1, prescreen: true, ..Default::default() };
+
+        let mut pending = prepare_ahead(&options, paths);
+        let shared = pending[0].handle.0.clone();
+        assert!(wait_for(&shared, |s| matches!(s.jobs[1], Job::Done(_))));
+        assert_eq!(shared.lock().held, HELD_PER_JOB);
+
+        /* Passing over files never taken makes room for the next ones */
+        for file in &mut pending[..2] {
+            file.release();
+        }
+        assert!(wait_for(&shared, |s| matches!(s.jobs[3], Job::Done(_))));
+        let prepared = pending[2].ready().unwrap().unwrap();
+        assert_eq!(prepared.size, 7000);
+        assert!(prepared.screened_size.is_some());
+    }
+}
@@ -0,0 +1,344 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::builder::{EntryReport, Options, Order, Priority};
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(Glob::new(pattern).with_context(|| format!("Invalid pattern {:?}", pattern))?);
+    }
+    set.build().context("Could not build patterns")
+}
+
+/// Decides by name which inputs are added, from [`Options::include`]
+/// and [`Options::exclude`], and their [`Options::priorities`] and
+/// [`Options::groups`].
+pub(crate) struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    priorities: GlobSet,
+    levels: Vec<Priority>,
+    groups: Vec<(String, GlobSet, Vec<PathBuf>)>,
+}
+
+impl Filter {
+    pub(crate) fn new(options: &Options) -> Result<Self> {
+        let include =
+            if options.include.is_empty() { None } else { Some(glob_set(&options.include)?) };
+        let (patterns, levels): (Vec<_>, Vec<_>) = options.priorities.iter().cloned().unzip();
+        let groups = options
+            .groups
+            .iter()
+            .map(|g| Ok((g.name.clone(), glob_set(&g.patterns)?, g.paths.clone())))
+            .collect::<Result<_>>()?;
+        Ok(Filter {
+            include,
+            exclude: glob_set(&options.exclude)?,
+            priorities: glob_set(&patterns)?,
+            levels,
+            groups,
+        })
+    }
+
+    /// Name of the first group `entry` belongs to.
+    pub(crate) fn group(&self, entry: &EntryReport) -> Option<String> {
+        let below = |path: &PathBuf| entry.source.as_ref().is_some_and(|s| s.starts_with(path));
+        self.groups
+            .iter()
+            .find(|(_, patterns, paths)| patterns.is_match(&entry.name) || paths.iter().any(below))
+            .map(|(name, _, _)| name.clone())
+    }
+
+    /// Priority of an input stored as `name`.
+    pub(crate) fn priority(&self, name: &Path) -> Priority {
+        let first = self.priorities.matches(name).into_iter().min();
+        first.map_or(Priority::default(), |i| self.levels[i])
+    }
+
+    /// Whether to add an input stored as `name`.
+    pub(crate) fn matches(&self, name: &Path) -> bool {
+        self.include.as_ref().is_none_or(|include| include.is_match(name))
+            && !self.exclude.is_match(name)
+    }
+}
+
+/// All files below `dir`, sorted by name within each directory.
+///
+/// With `respect_ignore_files` whatever `.gitignore` and `.ignore` files
+/// in the walked directories exclude is left out.
+pub(crate) fn walk(dir: &Path, respect_ignore_files: bool) -> Result<Vec<PathBuf>> {
+    let walker = ignore::WalkBuilder::new(dir)
+        .standard_filters(false)
+        .git_ignore(respect_ignore_files)
+        .ignore(respect_ignore_files)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry.with_context(|| format!("Could not read {}", dir.display()))?;
+        if !entry.file_type().is_some_and(|t| t.is_dir()) {
+            files.push(entry.into_path());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Steps the capacity is divided into by [`knapsack`], sizes are rounded
+/// up to whole steps.
+const KNAPSACK_STEPS: u64 = 4096;
+
+/// Which of `items`, given as (size, value), to take for the largest
+/// total value with their sizes adding up to at most `capacity`.
+/// Between equally good choices earlier items are preferred.
+pub(crate) fn knapsack(capacity: u64, items: &[(u64, u64)]) -> Vec<bool> {
+    let step = std::cmp::max(capacity.div_ceil(KNAPSACK_STEPS), 1);
+    let cells = (capacity / step) as usize;
+    let steps = |size: u64| std::cmp::min(size.div_ceil(step), cells as u64 + 1) as usize;
+    /* Values are scaled down as far as needed for their total to fit */
+    let total: u128 = items.iter().map(|(_, value)| *value as u128).sum();
+    let shift = (u128::BITS - total.leading_zeros()).saturating_sub(u64::BITS);
+
+    /* best[c]: highest value of the items so far within c steps */
+    let mut best = vec![0; cells + 1];
+    let mut taken = Vec::with_capacity(items.len());
+    for (size, value) in items {
+        let size = steps(*size);
+        let value = value >> shift;
+        let mut take = vec![false; cells + 1];
+        for c in (size..=cells).rev() {
+            if best[c - size] + value > best[c] {
+                best[c] = best[c - size] + value;
+                take[c] = true;
+            }
+        }
+        taken.push(take);
+    }
+
+    let mut wanted = vec![false; items.len()];
+    let mut c = cells;
+    for (i, (size, _)) in items.iter().enumerate().rev() {
+        if taken[i][c] {
+            wanted[i] = true;
+            c -= steps(*size);
+        }
+    }
+    wanted
+}
+
+/// Interleaves `items` by the group `group` puts them in, taking the
+/// first of each group in turn, then the second and so on. Items in no
+/// group follow in their order.
+pub(crate) fn round_robin<T>(items: Vec<T>, group: impl Fn(&T) -> Option<usize>) -> Vec<T> {
+    let mut groups: Vec<VecDeque<T>> = Vec::new();
+    let mut rest = Vec::new();
+    for item in items {
+        match group(&item) {
+            Some(g) => {
+                if groups.len() <= g {
+                    groups.resize_with(g + 1, VecDeque::new);
+                }
+                groups[g].push_back(item);
+            }
+            None => rest.push(item),
+        }
+    }
+
+    let mut interleaved = Vec::new();
+    while groups.iter().any(|g| !g.is_empty()) {
+        interleaved.extend(groups.iter_mut().filter_map(|g| g.pop_front()));
+    }
+    interleaved.extend(rest);
+    interleaved
+}
+
+/// Unix timestamp in the file name of `name`: the last run of 9 or 10
+/// digits, or of 13 digits taken as milliseconds.
+fn embedded_timestamp(name: &Path) -> Option<u64> {
+    let name = name.file_name()?.to_str()?;
+    name.split(|c: char| !c.is_ascii_digit()).rev().find_map(|digits| match digits.len() {
+        9 | 10 => digits.parse().ok(),
+        13 => digits.parse::<u64>().ok().map(|ms| ms / 1000),
+        _ => None,
+    })
+}
+
+fn split_digits(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
+
+/// Compares names with runs of digits compared by their value.
+fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+    let (mut a, mut b) = (a.as_ref(), b.as_ref());
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let ((x, rest_a), (y, rest_b)) = (split_digits(a), split_digits(b));
+                let (value_x, value_y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = value_x
+                    .len()
+                    .cmp(&value_y.len())
+                    .then_with(|| value_x.cmp(value_y))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (rest_a, rest_b);
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(&y),
+            (Some(x), Some(_)) => (a, b) = (&a[x.len_utf8()..], &b[x.len_utf8()..]),
+        }
+    }
+}
+
+/* Ascending, but without a key last */
+fn ascending(a: Option<u64>, b: Option<u64>) -> Ordering {
+    (a.is_none(), a).cmp(&(b.is_none(), b))
+}
+
+/// How inputs compare in `order`. Used with a stable sort, so those
+/// that compare equal keep the order they were added in.
+pub(crate) fn compare(order: Order, a: &EntryReport, b: &EntryReport) -> Ordering {
+    let timestamps = || (embedded_timestamp(&a.name), embedded_timestamp(&b.name));
+    match order {
+        Order::Given => Ordering::Equal,
+        /* None sorts before any Some, so descending puts it last */
+        Order::MtimeNewest => b.mtime.cmp(&a.mtime),
+        Order::MtimeOldest => ascending(a.mtime, b.mtime),
+        Order::SizeAscending => a.size.cmp(&b.size),
+        Order::Name => natural_cmp(&a.name, &b.name),
+        Order::TimestampNewest => {
+            let (a, b) = timestamps();
+            b.cmp(&a)
+        }
+        Order::TimestampOldest => {
+            let (a, b) = timestamps();
+            ascending(a, b)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let options = Options {
+            include: vec!["*.json".into(), "logs/**".into()],
+            exclude: vec!["*-private.json".into()],
+            ..Default::default()
+        };
+        let filter = Filter::new(&options).unwrap();
+
+        assert!(filter.matches(Path::new("dumps/slowness-1724087161.json")));
+        assert!(filter.matches(Path::new("logs/app.log")));
+        assert!(!filter.matches(Path::new("dumps/core")));
+        assert!(!filter.matches(Path::new("dumps/slowness-private.json")));
+
+        let options = Options { exclude: vec!["[".into()], ..Default::default() };
+        assert!(Filter::new(&options).is_err());
+
+        let options = Options {
+            priorities: vec![
+                ("dumps/summary.txt".into(), Priority::Must),
+                ("*.txt".into(), Priority::Weight(-1)),
+                ("dumps/**".into(), Priority::Weight(10)),
+            ],
+            ..Default::default()
+        };
+        let filter = Filter::new(&options).unwrap();
+        assert_eq!(filter.priority(Path::new("dumps/summary.txt")), Priority::Must);
+        assert_eq!(filter.priority(Path::new("dumps/notes.txt")), Priority::Weight(-1));
+        assert_eq!(filter.priority(Path::new("dumps/core")), Priority::Weight(10));
+        assert_eq!(filter.priority(Path::new("core")), Priority::Weight(0));
+        assert!(Priority::Must > Priority::Weight(i64::MAX));
+
+        let group = |name: &str, patterns: &[&str], paths: &[&str]| crate::Group {
+            name: name.into(),
+            share: crate::Share::Percent(50),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            paths: paths.iter().map(PathBuf::from).collect(),
+        };
+        let options = Options {
+            groups: vec![group("api", &["api/**"], &[]), group("db", &["*.sql"], &["/srv/db"])],
+            ..Default::default()
+        };
+        let filter = Filter::new(&options).unwrap();
+        let entry =
+            |source: &str, name: &str| EntryReport::new(Some(source.into()), name.into(), 0);
+        assert_eq!(filter.group(&entry("/srv/api/x.sql", "api/x.sql")).unwrap(), "api");
+        assert_eq!(filter.group(&entry("/srv/db/dump", "srv/db/dump")).unwrap(), "db");
+        assert_eq!(filter.group(&entry("/srv/dbx/dump", "srv/dbx/dump")), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let entry = |name: &str, size, mtime| EntryReport {
+            mtime,
+            ..EntryReport::new(None, name.into(), size)
+        };
+        let entries = [
+            entry("dumps/slowness-1724087161.json", 300, Some(20)),
+            entry("dumps/summary.txt", 100, None),
+            entry("dumps/slowness-1724000000123.json", 200, Some(30)),
+            entry("dumps/slowness-999999999.json", 200, Some(10)),
+        ];
+        let sorted = |order| {
+            let mut sorted: Vec<_> = (0..entries.len()).collect();
+            sorted.sort_by(|a, b| compare(order, &entries[*a], &entries[*b]));
+            sorted
+        };
+
+        assert_eq!(sorted(Order::Given), [0, 1, 2, 3]);
+        assert_eq!(sorted(Order::MtimeNewest), [2, 0, 3, 1]);
+        assert_eq!(sorted(Order::MtimeOldest), [3, 0, 2, 1]);
+        assert_eq!(sorted(Order::SizeAscending), [1, 2, 3, 0]);
+        assert_eq!(sorted(Order::Name), [3, 0, 2, 1]);
+        assert_eq!(sorted(Order::TimestampNewest), [0, 2, 3, 1]);
+        assert_eq!(sorted(Order::TimestampOldest), [3, 2, 0, 1]);
+
+        assert_eq!(natural_cmp(Path::new("dump-9"), Path::new("dump-10")), Ordering::Less);
+        assert_eq!(natural_cmp(Path::new("dump-010"), Path::new("dump-10")), Ordering::Greater);
+        assert_eq!(natural_cmp(Path::new("dump"), Path::new("dump-1")), Ordering::Less);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let items = vec![(0, 'a'), (0, 'b'), (2, 'c'), (9, 'd'), (0, 'e'), (2, 'f')];
+        let group = |(g, _): &(usize, char)| (*g != 9).then_some(*g);
+        let interleaved: String = round_robin(items, group).into_iter().map(|(_, c)| c).collect();
+        assert_eq!(interleaved, "acbfed");
+    }
+
+    #[test]
+    fn test_knapsack() {
+        let items = [(8000, 1), (3000, 1), (3000, 1), (3000, 1)];
+        assert_eq!(knapsack(10000, &items), [false, true, true, true]);
+        let items = [(6000, 1), (3000, 1), (4000, 1), (5000, 1)];
+        assert_eq!(knapsack(10000, &items), [true, true, false, false]);
+
+        let items = [(6000, 6000), (3000, 3000), (4000, 4000), (5000, 5000)];
+        assert_eq!(knapsack(10500, &items), [true, false, true, false]);
+
+        /* Sizes are rounded up, so it does not quite fit */
+        assert_eq!(knapsack(10000, &[(5000, 1), (5001, 1)]), [true, false]);
+        assert_eq!(knapsack(0, &[(0, 1), (1, 1)]), [true, false]);
+        assert_eq!(knapsack(10000, &[(u64::MAX, 1)]), [false]);
+
+        /* Values adding up past u64::MAX */
+        let items = [(6000, u64::MAX / 2), (3000, u64::MAX / 4), (4000, u64::MAX / 3)];
+        assert_eq!(knapsack(10500, &items), [true, false, true]);
+    }
+}
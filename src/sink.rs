@@ -0,0 +1,345 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+/// Where the compressed archive goes.
+///
+/// Everything written since the last [`commit`](Self::commit) may still
+/// be dropped again by [`rollback`](Self::rollback).
+pub(crate) trait Sink: Write {
+    /// Number of bytes written, including those not committed yet.
+    fn position(&self) -> u64;
+
+    /// Makes everything written so far permanent.
+    fn commit(&mut self) -> std::io::Result<()>;
+
+    /// Drops everything written since the last commit.
+    fn rollback(&mut self) -> std::io::Result<()>;
+}
+
+/// Writes directly to a (new, empty) file, which is truncated on rollback.
+pub(crate) struct FileSink {
+    file: File,
+    committed: u64,
+    pos: u64,
+}
+
+impl FileSink {
+    pub(crate) fn new(file: File) -> Self {
+        FileSink { file, committed: 0, pos: 0 }
+    }
+}
+
+impl Write for FileSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Sink for FileSink {
+    fn position(&self) -> u64 {
+        self.pos
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        self.committed = self.pos;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> std::io::Result<()> {
+        self.file.set_len(self.committed)?;
+        self.file.seek(SeekFrom::Start(self.committed))?;
+        self.pos = self.committed;
+        Ok(())
+    }
+}
+
+/// Writes to a (new, empty) file that is only created once more than
+/// what came before the first commit is committed, e.g. a volume that
+/// must not exist unless something is stored in it. Until then what is
+/// written is held in memory.
+pub(crate) struct LazyFileSink<'s> {
+    create: Option<Box<dyn FnOnce() -> std::io::Result<File> + 's>>,
+    file: Option<FileSink>,
+    held: Vec<u8>,
+    /// Length of `held` at the first and at the last commit.
+    first: Option<usize>,
+    committed: usize,
+}
+
+impl<'s> LazyFileSink<'s> {
+    pub(crate) fn new(create: impl FnOnce() -> std::io::Result<File> + 's) -> Self {
+        LazyFileSink {
+            create: Some(Box::new(create)),
+            file: None,
+            held: Vec::new(),
+            first: None,
+            committed: 0,
+        }
+    }
+}
+
+impl Write for LazyFileSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => {
+                self.held.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Sink for LazyFileSink<'_> {
+    fn position(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.position(),
+            None => self.held.len() as u64,
+        }
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        if let Some(file) = &mut self.file {
+            return file.commit();
+        }
+        match self.first {
+            Some(first) if self.held.len() > first => {
+                let create = self.create.take().expect("created only once");
+                let mut file = FileSink::new(create()?);
+                file.write_all(&std::mem::take(&mut self.held))?;
+                file.commit()?;
+                self.file = Some(file);
+            }
+            Some(_) => self.committed = self.held.len(),
+            None => {
+                self.first = Some(self.held.len());
+                self.committed = self.held.len();
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.rollback(),
+            None => {
+                self.held.truncate(self.committed);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes to anything, e.g. a pipe or socket, holding back the bytes
+/// written since the last commit.
+///
+/// Bytes beyond `limit` can never be committed (the archive would be
+/// over budget), so those are only counted and not kept in memory.
+pub(crate) struct StreamSink<W> {
+    inner: W,
+    limit: u64,
+    committed: u64,
+    pending: Vec<u8>,
+    /// Uncommitted bytes beyond `limit`.
+    dropped: u64,
+}
+
+impl<W: Write> StreamSink<W> {
+    pub(crate) fn new(inner: W, limit: u64) -> Self {
+        StreamSink { inner, limit, committed: 0, pending: Vec::new(), dropped: 0 }
+    }
+}
+
+impl<W: Write> Write for StreamSink<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let room = self.limit.saturating_sub(self.committed + self.pending.len() as u64);
+        let kept = std::cmp::min(room, buf.len() as u64) as usize;
+        self.pending.extend_from_slice(&buf[..kept]);
+        self.dropped += (buf.len() - kept) as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for StreamSink<W> {
+    fn position(&self) -> u64 {
+        self.committed + self.pending.len() as u64 + self.dropped
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        if self.dropped > 0 {
+            return Err(std::io::Error::other("Output exceeds the size limit"));
+        }
+        self.inner.write_all(&self.pending)?;
+        self.inner.flush()?;
+        self.committed += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> std::io::Result<()> {
+        self.pending.clear();
+        self.dropped = 0;
+        Ok(())
+    }
+}
+
+/// Only counts what is written, for passes that just find out what
+/// fits.
+#[derive(Default)]
+pub(crate) struct NullSink {
+    committed: u64,
+    pending: u64,
+}
+
+impl Write for NullSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for NullSink {
+    fn position(&self) -> u64 {
+        self.committed + self.pending
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        self.committed += self.pending;
+        self.pending = 0;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> std::io::Result<()> {
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+/// The sinks of a [`TeeSink`], each taken out once it is complete.
+pub(crate) type Tees<'s> = Rc<RefCell<Vec<Option<Box<dyn Sink + 's>>>>>;
+
+/// Writes the same to several sinks, for outputs that share their
+/// beginning but are cut back at different points.
+pub(crate) struct TeeSink<'s> {
+    sinks: Tees<'s>,
+    committed: u64,
+    pos: u64,
+}
+
+impl<'s> TeeSink<'s> {
+    pub(crate) fn new(sinks: Vec<Box<dyn Sink + 's>>) -> (Self, Tees<'s>) {
+        let sinks = Rc::new(RefCell::new(sinks.into_iter().map(Some).collect()));
+        (TeeSink { sinks: Rc::clone(&sinks), committed: 0, pos: 0 }, sinks)
+    }
+}
+
+impl Write for TeeSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for sink in self.sinks.borrow_mut().iter_mut().flatten() {
+            sink.write_all(buf)?;
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for TeeSink<'_> {
+    fn position(&self) -> u64 {
+        self.pos
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        for sink in self.sinks.borrow_mut().iter_mut().flatten() {
+            sink.commit()?;
+        }
+        self.committed = self.pos;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> std::io::Result<()> {
+        for sink in self.sinks.borrow_mut().iter_mut().flatten() {
+            sink.rollback()?;
+        }
+        self.pos = self.committed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_sink() {
+        let mut out = Vec::new();
+        let mut sink = StreamSink::new(&mut out, 10);
+
+        sink.write_all(b"abcd").unwrap();
+        sink.commit().unwrap();
+        sink.write_all(b"efgh").unwrap();
+        sink.rollback().unwrap();
+        assert_eq!(sink.position(), 4);
+
+        sink.write_all(b"0123456789").unwrap();
+        assert_eq!(sink.position(), 14);
+        assert!(sink.commit().is_err());
+        sink.rollback().unwrap();
+
+        sink.write_all(b"ijklmn").unwrap();
+        sink.commit().unwrap();
+        assert_eq!(sink.position(), 10);
+        drop(sink);
+
+        assert_eq!(out, b"abcdijklmn");
+    }
+
+    #[test]
+    fn test_lazy_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("volume");
+        let mut sink = LazyFileSink::new(|| File::create_new(&path));
+
+        sink.write_all(b"head").unwrap();
+        sink.commit().unwrap();
+        sink.write_all(b"abcd").unwrap();
+        sink.rollback().unwrap();
+        sink.commit().unwrap();
+        assert!(!path.exists());
+
+        sink.write_all(b"efgh").unwrap();
+        sink.commit().unwrap();
+        sink.write_all(b"ijkl").unwrap();
+        sink.rollback().unwrap();
+        assert_eq!(sink.position(), 8);
+        drop(sink);
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"headefgh");
+    }
+}
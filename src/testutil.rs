@@ -0,0 +1,60 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// Deterministic data that brotli can't compress.
+pub(crate) fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    (0..len)
+        .map(|_| {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (x >> 56) as u8
+        })
+        .collect()
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The tar stream in `file`, brotli, zstd or gzip compressed or not at all.
+pub(crate) fn decompress<R: Read + Seek>(mut file: R) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut compressed).unwrap();
+
+    if compressed.get(257..262) == Some(b"ustar") {
+        return compressed;
+    }
+    if compressed.starts_with(&ZSTD_MAGIC) {
+        return zstd::decode_all(&compressed[..]).expect("valid zstd stream");
+    }
+
+    let mut tar = Vec::new();
+    if compressed.starts_with(&GZIP_MAGIC) {
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut tar)
+            .expect("valid gzip stream");
+        return tar;
+    }
+
+    brotli::Decompressor::new(&compressed[..], 4096)
+        .read_to_end(&mut tar)
+        .expect("valid brotli stream");
+    tar
+}
+
+/// Decompresses `file` and returns the name and contents of every entry.
+pub(crate) fn read_archive<R: Read + Seek>(file: R) -> Vec<(PathBuf, Vec<u8>)> {
+    let tar = decompress(file);
+
+    let mut archive = tar::Archive::new(&tar[..]);
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            (entry.path().unwrap().into_owned(), data)
+        })
+        .collect()
+}
@@ -0,0 +1,110 @@
+//! Picking the brotli quality by compressing a sample of the inputs at
+//! several qualities, see [`Options::max_time`] and
+//! [`Options::auto_quality`], and estimating quickly how inputs compress
+//! for [`Options::prescreen`].
+
+use std::io::Write;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use crate::builder::{BrotliParams, Format, Options};
+use crate::compressor::{new_compressor, Compression, Output};
+use crate::sink::NullSink;
+
+/// Qualities tried, best compression first.
+const QUALITIES: [u32; 6] = [11, 9, 7, 5, 3, 1];
+
+/// How one quality did on the sample.
+struct Trial {
+    /// Compressed bytes per input byte.
+    ratio: f64,
+    /// Seconds per input byte.
+    pace: f64,
+}
+
+fn trial(sample: &[u8], brotli: BrotliParams) -> Result<Trial> {
+    let output = Output { sink: Some(Box::new(NullSink::default())), skip: 0 };
+    let start = Instant::now();
+    let mut compressor = new_compressor(Compression { format: Format::Brotli, brotli }, output)?;
+    compressor.write_all(sample).context("Could not compress sample")?;
+    let output = compressor.finish().context("Could not compress sample")?;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let compressed = output.sink.map_or(0, |sink| sink.position());
+    let len = sample.len().max(1) as f64;
+    Ok(Trial { ratio: compressed as f64 / len, pace: elapsed / len })
+}
+
+/// Compressed bytes per byte of `sample` at the fastest brotli quality.
+pub(crate) fn quick_ratio(sample: &[u8]) -> Result<f64> {
+    let fastest = QUALITIES[QUALITIES.len() - 1];
+    Ok(trial(sample, BrotliParams { quality: fastest, ..BrotliParams::default() })?.ratio)
+}
+
+/// Number of inputs of `sizes`, tried in order, that fit in `capacity`
+/// when compressed by `ratio`.
+fn fitting(sizes: &[u64], capacity: u64, ratio: f64) -> usize {
+    let mut used = 0.0;
+    sizes
+        .iter()
+        .take_while(|size| {
+            used += **size as f64 * ratio;
+            used <= capacity as f64
+        })
+        .count()
+}
+
+/// The brotli quality to compress inputs of `sizes` with, going by how
+/// `sample` of them compresses.
+///
+/// Going from the best quality down, the first one that is projected to
+/// compress all inputs within [`Options::max_time`] is taken, or with
+/// [`Options::auto_quality`] the one of those that fits the most inputs
+/// in `capacity`, the faster one on a tie. When no quality is fast
+/// enough the fastest is taken.
+pub(crate) fn choose_quality(
+    options: &Options,
+    sample: &[u8],
+    sizes: &[u64],
+    capacity: u64,
+) -> Result<u32> {
+    let total: u64 = sizes.iter().sum();
+    let mut best: Option<(usize, u32)> = None;
+    for quality in QUALITIES {
+        let Trial { ratio, pace } = trial(sample, BrotliParams { quality, ..options.brotli })?;
+        let seconds = pace * total as f64;
+        let count = fitting(sizes, capacity, ratio);
+        options.note(format_args!(
+            "Quality {}: compresses to {:.1}%, projected {:.1} seconds and {} files.",
+            quality,
+            ratio * 100.0,
+            seconds,
+            count
+        ));
+
+        if options.max_time.is_some_and(|limit| seconds > limit.as_secs_f64()) {
+            continue;
+        }
+        if !options.auto_quality {
+            return Ok(quality);
+        }
+        if best.is_none_or(|(most, _)| count >= most) {
+            best = Some((count, quality));
+        }
+    }
+
+    Ok(best.map_or(QUALITIES[QUALITIES.len() - 1], |(_, quality)| quality))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fitting() {
+        assert_eq!(fitting(&[100, 100, 100], 150, 0.5), 3);
+        assert_eq!(fitting(&[100, 100, 100], 150, 0.6), 2);
+        assert_eq!(fitting(&[400, 100], 150, 0.5), 0);
+    }
+}
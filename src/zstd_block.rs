@@ -0,0 +1,89 @@
+//! Zstandard blocks written by hand, used to complete a frame that has
+//! been cut back to a checkpoint [RFC8878].
+//!
+//! Raw and RLE blocks hold their content as is, so they don't depend on
+//! the encoder state at the checkpoint.
+
+/// Size of the uncompressed blocks. Blocks may not be larger than the
+/// window, which is at least 512 KiB for all compression levels when
+/// the content size is not known up front.
+const RAW_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Frame header [RFC8878 3.1.1] for when the encoder has not written
+/// one yet: no content size, no checksum, no dictionary and a 1 MiB
+/// window (Exponent 10, Mantissa 0), enough for the raw blocks.
+pub(crate) const FRAME_HEADER: [u8; 6] = [0x28, 0xb5, 0x2f, 0xfd, 0x00, 10 << 3];
+
+/// Block header [RFC8878 3.1.1.2]: Last_Block (1 bit), Block_Type (2
+/// bits), Block_Size (21 bits), little endian.
+fn block_header(last: bool, block_type: u32, size: usize, out: &mut Vec<u8>) {
+    let header = last as u32 | (block_type << 1) | ((size as u32) << 3);
+    out.extend_from_slice(&header.to_le_bytes()[..3]);
+}
+
+/// Appends `data` as raw blocks.
+pub(crate) fn raw(data: &[u8], out: &mut Vec<u8>) {
+    for chunk in data.chunks(RAW_BLOCK_SIZE) {
+        block_header(false, 0, chunk.len(), out);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Number of bytes [`raw`] appends for `len` bytes of data.
+pub(crate) fn raw_size(len: u64) -> u64 {
+    len + 3 * len.div_ceil(RAW_BLOCK_SIZE as u64)
+}
+
+/// The last block of a frame, `len` zero bytes as a RLE block.
+///
+/// Frames are written without checksum, so nothing follows it.
+pub(crate) fn zeros_and_last(len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4);
+    block_header(true, 1, len, &mut out);
+    out.push(0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_complete_truncated_frame() {
+        let mut stream = Vec::new();
+        let kept_len;
+        {
+            let mut encoder = zstd::stream::write::Encoder::new(&mut stream, 19).unwrap();
+            encoder.include_checksum(false).unwrap();
+            encoder.write_all(b"kept").unwrap();
+            encoder.flush().unwrap();
+            kept_len = encoder.get_ref().len();
+            encoder.write_all(b"cut off").unwrap();
+            encoder.finish().unwrap();
+        }
+        stream.truncate(kept_len);
+
+        let tail = crate::testutil::noise(70000, 1);
+        raw(&tail, &mut stream);
+        stream.extend_from_slice(&zeros_and_last(1024));
+
+        let mut expected = b"kept".to_vec();
+        expected.extend_from_slice(&tail);
+        expected.extend_from_slice(&[0; 1024]);
+        assert_eq!(zstd::decode_all(&stream[..]).unwrap(), expected);
+
+        assert_eq!(raw_size(70000), 70006);
+    }
+
+    #[test]
+    fn test_frame_without_encoder() {
+        let mut stream = FRAME_HEADER.to_vec();
+        raw(b"tail", &mut stream);
+        stream.extend_from_slice(&zeros_and_last(1024));
+
+        let mut expected = b"tail".to_vec();
+        expected.extend_from_slice(&[0; 1024]);
+        assert_eq!(zstd::decode_all(&stream[..]).unwrap(), expected);
+    }
+}